@@ -1,3 +1,6 @@
+use crate::models::profile::crypto::Padding;
+use crate::models::secret::Secret;
+use clap::builder::TypedValueParser;
 use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
@@ -10,6 +13,14 @@ pub struct Args {
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbosity: u8,
 
+    /// Config file. Defaults to softsim/config.toml in the user's config directory
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Named profile of the config file, e.g. staging or prod
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
     #[clap(subcommand)]
     pub cmd: SubCommand,
 }
@@ -18,61 +29,378 @@ pub struct Args {
 pub enum SubCommand {
     /// Fetch profiles from API
     Fetch {
-        #[arg(short, long)]
-        api_key: String,
+        #[command(flatten)]
+        api_key: ApiKeyArgs,
         #[arg(short, long = "count", default_value = "1")]
         num_of_profiles: u32,
-        #[arg(short, long = "out", default_value = "profiles")]
-        output: PathBuf,
-        #[arg(short, long, default_value = "https://api.onomondo.com/sims/profiles")]
-        url: String,
+        /// Directory to write the profiles to. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short, long = "out")]
+        output: Option<PathBuf>,
+        /// SoftSIM API endpoint. Defaults to url of the config file or https://api.onomondo.com/sims/profiles
+        #[arg(short, long)]
+        url: Option<String>,
+        /// Number of retries for calls turned away (429, 503) or that failed to connect.
+        /// Other failures aren't retried, the API may have handed out profiles already
+        #[arg(long, default_value = "5")]
+        retries: u32,
+        /// Delay in milliseconds before the first retry. Doubled for every retry.
+        /// A Retry-After header from the API takes precedence, up to a minute
+        #[arg(long, value_name = "MS", default_value = "1000")]
+        retry_delay: u64,
     },
     /// Find next available profile. Decrypt and decode the profile and mark it as used.
     Next {
-        /// Path to private key
+        #[command(flatten)]
+        key: KeyArgs,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Number of profiles to hand out. Either all of them are claimed or none
+        #[arg(short = 'n', long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+        #[command(flatten)]
+        reserve: ReserveArgs,
+        /// Serial number of the device the profile is written to. Recorded in the audit log
+        #[arg(long)]
+        serial: Option<String>,
+        /// IMEI or serial number of the device. Binds the profile to the device, and
+        /// hands out the same profile again if the device already has one
+        #[arg(long, conflicts_with = "count")]
+        device_id: Option<String>,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Add the profiles of a profiles.json or an API response to a profile directory.
+    Import {
+        /// profiles.json written by `fetch`, or a saved response of the profiles API
+        file: PathBuf,
+        /// Profile directory to add the profiles to. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Generate a key pair. Upload the public key to create a SoftSIM API key.
+    Keygen {
+        /// Key size in bits
+        #[arg(short, long, default_value = "4096", value_parser = clap::value_parser!(u32).range(2048..=8192))]
+        bits: u32,
+        /// Path to write the private key to. The public key is written to <OUT>.pub
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Count available and consumed profiles.
+    Status {
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Exit with code 2 if fewer profiles than this are available
+        #[arg(long, value_name = "N")]
+        min_available: Option<usize>,
+    },
+    /// Return a consumed profile to the pool, e.g. after flashing a board failed.
+    Release {
+        /// ICCID of the profile to release
+        iccid: String,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Why the profile is released. Recorded in the audit log
         #[arg(short, long)]
-        key: PathBuf,
-        /// Path to encrypted profiles.
-        #[arg(short = 'i', long = "in", default_value = "./profiles")]
+        reason: String,
+        /// Who releases the profile. Defaults to the current user
+        #[arg(long)]
+        by: Option<String>,
+    },
+    /// Confirm that a profile was written to a device. Confirmed profiles can't be released.
+    Confirm {
+        /// ICCID of the provisioned profile
+        iccid: String,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Who confirms the profile. Defaults to the current user
+        #[arg(long)]
+        by: Option<String>,
+    },
+    /// Mark reserved profiles as provisioned once the device confirmed the write.
+    Commit {
+        /// Reservation token printed by `next --reserve`
+        token: String,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+    },
+    /// Return reserved profiles to the pool.
+    Abort {
+        /// Reservation token printed by `next --reserve`
+        token: String,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+    },
+    /// Trial-decrypt profiles to check that they match the key, without marking them as used.
+    Verify {
+        #[command(flatten)]
+        key: KeyArgs,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
         set_of_profiles: Option<PathBuf>,
-        /// Output format.
+        /// Only check a random sample of this many profiles
+        #[arg(long, value_name = "N")]
+        sample: Option<usize>,
+    },
+    /// Encrypt every profile of a pool to a new key, e.g. when the old private key is compromised.
+    Rekey {
+        /// Private key the profiles are encrypted to now
+        #[arg(long)]
+        old_key: PathBuf,
+        /// Where to read the passphrase of an encrypted old key from. See --passphrase of next
+        #[arg(long, value_name = "SOURCE", default_value = "prompt")]
+        passphrase: Secret,
+        /// RSA padding of profiles whose envelope doesn't name it: auto, oaep-sha1,
+        /// oaep-sha256 or pkcs1v15
+        #[arg(long, default_value = "auto")]
+        padding: Padding,
+        /// Public key to encrypt the profiles to. SPKI or PKCS#1, PEM or DER encoded
+        #[arg(long)]
+        new_pubkey: PathBuf,
+        /// RSA padding to encrypt the profiles with. PKCS#1 v1.5 is only read, never written
         #[arg(
             long,
-            require_equals = true,
-            value_name = "FORMAT",
-            num_args = 0..=1,
-            default_value_t = Format::Hex,
-            default_missing_value = "hex",
-            value_enum
+            default_value = "oaep-sha1",
+            value_parser = clap::builder::PossibleValuesParser::new(["oaep-sha1", "oaep-sha256"])
+                .map(|s| s.parse::<Padding>().unwrap())
         )]
-        format: Format,
-        /// Include SMSP TLV in output when present in profile
-        #[arg(long = "smsp")]
-        smsp: bool,
-        /// Do not include SMSC TLV in output when present in profile.
-        /// This can reduce profile size for SoftSIMs that do not support SMS
-        #[arg(long = "no-smsc")]
-        no_smsc: bool,
+        new_padding: Padding,
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Check that every profile can be re-encrypted, without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move unused profiles into sub-pools, e.g. one per production line.
+    #[command(group(clap::ArgGroup::new("how").required(true)))]
+    Split {
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Number of parts of equal size. All unused profiles are moved
+        #[arg(long, group = "how", value_parser = clap::value_parser!(u32).range(2..))]
+        parts: Option<u32>,
+        /// Size of each part, e.g. 5000,3000,2000. Profiles left over stay in the pool
+        #[arg(long, group = "how", value_delimiter = ',', value_parser = clap::value_parser!(u64).range(1..))]
+        sizes: Option<Vec<u64>>,
+        /// Directory to create the part directories and manifests in. Defaults to --in
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Find the device a profile was handed out to, or the profile of a device or IMSI.
+    #[command(group(clap::ArgGroup::new("query").required(true)))]
+    Lookup {
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// Print the device id bound to this ICCID
+        #[arg(long, group = "query")]
+        iccid: Option<String>,
+        /// Print the ICCID bound to this device id
+        #[arg(long, group = "query")]
+        device_id: Option<String>,
+        /// Print the ICCID of the handed out profile with this IMSI
+        #[arg(long, group = "query")]
+        imsi: Option<String>,
+    },
+    /// Compare the profiles used locally with the SIMs active on the platform.
+    Reconcile {
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// ICCIDs exported from the platform. One per line, a CSV with an iccid column
+        /// or JSON. The SIMs are listed through the API when omitted
+        #[arg(long, value_name = "FILE")]
+        active: Option<PathBuf>,
+        #[command(flatten)]
+        api_key: ApiKeyArgs,
+        #[arg(short, long, default_value = "https://api.onomondo.com/sims")]
+        url: String,
+        /// Number of retries for failed calls (408, 429, 500, 502, 503, 504, timeouts and
+        /// connection errors). Listing SIMs changes nothing, so unlike fetch every one is retried
+        #[arg(long, default_value = "5")]
+        retries: u32,
+        /// Delay in milliseconds before the first retry. Doubled for every retry.
+        /// A Retry-After header from the API takes precedence, up to a minute
+        #[arg(long, value_name = "MS", default_value = "1000")]
+        retry_delay: u64,
+    },
+    /// Import a profile directory into a SQLite database.
+    #[cfg(feature = "sqlite")]
+    Migrate {
+        /// Profile directory to import
+        #[arg(long)]
+        from: PathBuf,
+        /// Database to create or add to, ending in .db, .sqlite or .sqlite3
+        #[arg(long)]
+        to: PathBuf,
+    },
+    /// Work with the audit log of a profile directory.
+    Audit {
+        #[clap(subcommand)]
+        cmd: AuditCommand,
+    },
+    /// Set the profiles path, API endpoint and API key used when no flag or
+    /// environment variable gives them. Prints the settings when called without options.
+    Configure {
+        /// Default path to encrypted profiles, for --in and --out
+        #[arg(long)]
+        profiles_path: Option<PathBuf>,
+        /// SoftSIM API endpoint
+        #[arg(short, long)]
+        url: Option<String>,
+        /// File to read the API key from. Keeps the key itself out of the config file
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["api_key_stdin", "api_key_from"])]
+        api_key_file: Option<PathBuf>,
+        /// Where to read the API key from, e.g. cmd:COMMAND or vault:PATH#FIELD.
+        /// See --api-key of fetch
+        #[arg(long, value_name = "SOURCE", conflicts_with = "api_key_stdin")]
+        api_key_from: Option<Secret>,
+        /// Read the API key from stdin and store it in the config file
+        #[arg(long)]
+        api_key_stdin: bool,
+    },
+    /// Decode a hex encoded profile, e.g. as read back from a device.
+    Decode {
+        /// Hex encoded profile. Read from stdin when omitted.
+        hex: Option<String>,
+        /// Print K, OPc, KIc, KID and ADM in full instead of masked
+        #[arg(long)]
+        show_secrets: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuditCommand {
+    /// Export the audit log as CSV, one row per profile and event.
+    Export {
+        /// Path to encrypted profiles. Defaults to profiles_path of the config file or ./profiles
+        #[arg(short = 'i', long = "in")]
+        set_of_profiles: Option<PathBuf>,
+        /// First day to export, as YYYY-MM-DD in UTC
+        #[arg(long, value_name = "DATE")]
+        from: Option<chrono::NaiveDate>,
+        /// Last day to export, as YYYY-MM-DD in UTC
+        #[arg(long, value_name = "DATE")]
+        to: Option<chrono::NaiveDate>,
+        /// Write the CSV to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
     },
 }
 
+#[derive(clap::Args, Debug)]
+pub struct KeyArgs {
+    /// Path to private key. PKCS#1 or PKCS#8, PEM or DER encoded
+    #[arg(short, long)]
+    pub key: PathBuf,
+    /// Where to read the passphrase of an encrypted key from: prompt, env:VAR, file:PATH,
+    /// fd:N, cmd:COMMAND or vault:PATH#FIELD. Only read when needed [default: prompt]
+    #[arg(long, value_name = "SOURCE", conflicts_with_all = ["passphrase_env", "passphrase_fd"])]
+    pub passphrase: Option<Secret>,
+    /// Read the passphrase of an encrypted key from this environment variable.
+    /// Same as --passphrase env:VAR
+    #[arg(long, value_name = "VAR", conflicts_with = "passphrase_fd")]
+    pub passphrase_env: Option<String>,
+    /// Read the passphrase of an encrypted key from this file descriptor.
+    /// Same as --passphrase fd:FD, Unix only
+    #[arg(long, value_name = "FD")]
+    pub passphrase_fd: Option<u32>,
+    /// RSA padding of profiles whose envelope doesn't name it: auto, oaep-sha1,
+    /// oaep-sha256 or pkcs1v15
+    #[arg(long, default_value = "auto")]
+    pub padding: Padding,
+}
+
+impl KeyArgs {
+    pub fn passphrase(&self) -> Secret {
+        match (&self.passphrase, &self.passphrase_env, self.passphrase_fd) {
+            (Some(secret), _, _) => secret.clone(),
+            (None, Some(var), _) => Secret::Env(var.clone()),
+            (None, None, Some(fd)) => Secret::Fd(fd),
+            (None, None, None) => Secret::Prompt,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ApiKeyArgs {
+    /// Where to read the SoftSIM API key from: env:VAR, file:PATH, fd:N, cmd:COMMAND,
+    /// vault:PATH#FIELD or prompt. The key itself isn't accepted, the command line
+    /// ends up in the shell history
+    #[arg(short, long, value_name = "SOURCE", conflicts_with = "api_key_file")]
+    pub api_key: Option<Secret>,
+    /// Read the API key from this file, or from stdin if FILE is -
+    #[arg(long, value_name = "FILE")]
+    pub api_key_file: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ReserveArgs {
+    /// Reserve the profiles instead of consuming them. Prints a reservation token
+    /// to stderr, to be passed to `commit` or `abort`
+    #[arg(long)]
+    pub reserve: bool,
+    /// Seconds before an uncommitted reservation is returned to the pool
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "900",
+        requires = "reserve"
+    )]
+    pub ttl: u64,
+    /// Write the reservation token to this file instead of stderr, where it mixes
+    /// with the log
+    #[arg(long, value_name = "FILE", requires = "reserve")]
+    pub token_file: Option<PathBuf>,
+}
+
+impl ReserveArgs {
+    /// How long the reservation lasts, `None` without `--reserve`.
+    pub fn ttl(&self) -> Option<std::time::Duration> {
+        self.reserve
+            .then_some(std::time::Duration::from_secs(self.ttl))
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct OutputArgs {
+    /// Output format.
+    #[arg(
+        long,
+        require_equals = true,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_value_t = Format::Hex,
+        default_missing_value = "hex",
+        value_enum
+    )]
+    pub format: Format,
+    /// Include SMSP TLV in output when present in profile
+    #[arg(long = "smsp")]
+    pub smsp: bool,
+    /// Do not include SMSC TLV in output when present in profile.
+    /// This can reduce profile size for SoftSIMs that do not support SMS
+    #[arg(long = "no-smsc")]
+    pub no_smsc: bool,
+    /// With --count, write one JSON profile per line instead of a JSON array
+    #[arg(long)]
+    pub lines: bool,
+    /// Print K, OPc, KIc, KID and ADM in full in the raw format, which masks them
+    #[arg(long)]
+    pub show_secrets: bool,
+}
+
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Format {
     Hex,
     Json,
     Raw
 }
-
-// /// Set persistent configuration. Export path, api endpoint, api key can be set.
-//   Configure {
-//       /// Export path for encrypted profiles
-//       #[arg(short, long = "profiles_path")]
-//       set_of_profiles: Option<PathBuf>,
-//       /// SoftSIM API key
-//       #[arg(short, long)]
-//       api_key: Option<String>,
-//       /// SoftSIM API endpoint
-//       /// Default: https://api.onomondo.com/sims/profile
-//       #[arg(short, long)]
-//       endpoint: Option<String>,
-//   },
@@ -1,12 +1,25 @@
 use chrono::Local;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use env_logger::Builder;
 use log::LevelFilter;
+use models::audit;
+use models::binding;
+use models::bundle;
+use models::checkpoint::Checkpoint;
 use models::fs;
+use models::pool;
 use models::profile;
+use models::reconcile;
+use models::rekey;
+use models::reservation::{self, Reservation};
+use models::secret::{mask, Secret};
+use models::settings::{self, Settings};
+use models::split;
+use models::store::{self, State, Store};
 use std::error::Error;
 use std::io::Write;
 use std::path::PathBuf;
+use zeroize::Zeroizing;
 
 mod config;
 mod models;
@@ -36,99 +49,227 @@ async fn main() {
 
     log::trace!("{:?}", arg);
 
+    let settings = match &arg.cmd {
+        // may be about to create the profile
+        config::SubCommand::Configure { .. } => Ok(Settings::default()),
+        _ => settings::load(arg.config.as_deref(), arg.profile.as_deref()),
+    };
+    let settings = match settings {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let pool = |flag: Option<PathBuf>| flag.unwrap_or_else(|| settings.profiles_path());
+
     let res = match arg.cmd {
         config::SubCommand::Fetch {
             api_key,
             num_of_profiles,
             output,
             url,
+            retries,
+            retry_delay,
         } => {
-            let api_config = models::profile::api::Config {
-                api_key: api_key.to_string(),
-                url: url.to_string(),
-            };
+            let settings = Settings {
+                profiles_path: output,
+                url,
+                api_key_file: api_key.api_key_file,
+                api_key_from: api_key.api_key,
+                ..Default::default()
+            }
+            .or(settings.clone());
+            let url = settings
+                .url
+                .clone()
+                .unwrap_or_else(|| profile::api::DEFAULT_URL.to_string());
 
-            fetch_profiles(&api_config, num_of_profiles, &mut output.clone()).await
+            async {
+                let api_config = api_config(&settings, url, retries, retry_delay)?;
+                fetch_profiles(&api_config, num_of_profiles, &mut settings.profiles_path()).await
+            }
+            .await
         }
         config::SubCommand::Next {
-            key: private_key,
+            key,
             set_of_profiles: base_path,
-            format,
-            smsp,
-            no_smsc,
-        } => next(&private_key, &base_path.unwrap(), format, smsp, !no_smsc),
+            count,
+            reserve,
+            serial,
+            device_id,
+            output,
+        } => next(
+            &key,
+            &pool(base_path),
+            count as usize,
+            &reserve,
+            serial,
+            device_id,
+            &output,
+        ),
+        config::SubCommand::Import { file, out } => import(&file, &pool(out)),
+        config::SubCommand::Keygen { bits, out } => keygen(bits as usize, &out),
+        config::SubCommand::Status {
+            set_of_profiles,
+            min_available,
+        } => status(&pool(set_of_profiles), min_available),
+        config::SubCommand::Release {
+            iccid,
+            set_of_profiles,
+            reason,
+            by,
+        } => release(&pool(set_of_profiles), &iccid, reason, by),
+        config::SubCommand::Confirm {
+            iccid,
+            set_of_profiles,
+            by,
+        } => confirm(&pool(set_of_profiles), &iccid, by),
+        config::SubCommand::Commit {
+            token,
+            set_of_profiles,
+        } => commit(&pool(set_of_profiles), &token),
+        config::SubCommand::Abort {
+            token,
+            set_of_profiles,
+        } => abort(&pool(set_of_profiles), &token),
+        config::SubCommand::Verify {
+            key,
+            set_of_profiles,
+            sample,
+        } => verify(&key, &pool(set_of_profiles), sample),
+        config::SubCommand::Rekey {
+            old_key,
+            passphrase,
+            padding,
+            new_pubkey,
+            new_padding,
+            set_of_profiles,
+            dry_run,
+        } => rekey(
+            &old_key,
+            &passphrase,
+            padding,
+            &new_pubkey,
+            new_padding,
+            &pool(set_of_profiles),
+            dry_run,
+        ),
+        config::SubCommand::Split {
+            set_of_profiles,
+            parts,
+            sizes,
+            out,
+        } => split(&pool(set_of_profiles), parts, sizes, out),
+        config::SubCommand::Lookup {
+            set_of_profiles,
+            iccid,
+            device_id,
+            imsi,
+        } => lookup(&pool(set_of_profiles), iccid, device_id, imsi),
+        config::SubCommand::Reconcile {
+            set_of_profiles,
+            active,
+            api_key,
+            url,
+            retries,
+            retry_delay,
+        } => {
+            let settings = Settings {
+                api_key_file: api_key.api_key_file,
+                api_key_from: api_key.api_key,
+                ..Default::default()
+            }
+            .or(settings.clone());
+
+            async {
+                // the API is only asked without an exported list
+                let api_config = match active {
+                    Some(_) => None,
+                    None => Some(api_config(&settings, url, retries, retry_delay)?),
+                };
+                reconcile(
+                    &pool(set_of_profiles),
+                    active.as_deref(),
+                    api_config.as_ref(),
+                )
+                .await
+            }
+            .await
+        }
+        #[cfg(feature = "sqlite")]
+        config::SubCommand::Migrate { from, to } => migrate(&from, &to),
+        config::SubCommand::Audit {
+            cmd:
+                config::AuditCommand::Export {
+                    set_of_profiles,
+                    from,
+                    to,
+                    out,
+                },
+        } => audit_export(&pool(set_of_profiles), from, to, out.as_deref()),
+        config::SubCommand::Configure {
+            profiles_path,
+            url,
+            api_key_file,
+            api_key_from,
+            api_key_stdin,
+        } => configure(
+            arg.config.as_deref(),
+            arg.profile.as_deref(),
+            Settings {
+                profiles_path,
+                url,
+                api_key: None,
+                api_key_file,
+                api_key_from,
+            },
+            api_key_stdin,
+        ),
+        config::SubCommand::Decode { hex, show_secrets } => decode(hex, show_secrets),
     };
 
     if let Err(res) = res {
         log::info!("Exiting with error");
         log::trace!("{:?}", res);
-        std::process::exit(1);
-    }
-}
-
-fn get_next(base_path: &PathBuf) -> Result<std::fs::DirEntry, Box<dyn Error>> {
-    // find next unused profile.
-    let next = std::fs::read_dir(base_path)
-        .map_err(|e| {
-            log::error!("Failed to read directory: {}", base_path.display());
-            format!(
-                "Failed to read directory: {} Err: {}",
-                base_path.display(),
-                e
-            )
-        })?
-        .find(|p| {
-            if let Ok(entry) = p {
-                let path = entry.path();
-                let current_path = path
-                    .file_stem()
-                    .unwrap_or_else(|| std::ffi::OsStr::new(""))
-                    .to_str()
-                    .unwrap();
-                if !current_path.starts_with("__")
-                    && !current_path.starts_with("profiles")
-                    && path
-                        .extension()
-                        .unwrap_or(std::ffi::OsStr::new("notjson"))
-                        .eq("json")
-                {
-                    return true;
-                }
-            }
-            false
-        });
-
-    match next {
-        None => {
-            log::error!("No profiles found at {}", base_path.display());
-            Err("No profiles was found".into())
+        // distinct exit code so monitoring can tell a low pool from a failure
+        if res.is::<pool::LowInventory>() {
+            std::process::exit(2);
         }
-        Some(val) => Ok(val?),
+        std::process::exit(1);
     }
 }
 
-fn mark_exported(path: &std::fs::DirEntry) -> Result<(), Box<dyn Error>> {
-    let mut to = path.path();
-    let mut filename = path.file_name().into_string().unwrap();
-    filename.insert_str(0, "__");
-
-    to.set_file_name(filename);
-    std::fs::rename(path.path(), to)?;
+/// API settings for `fetch` and `reconcile`. Fails without an API key.
+fn api_config(
+    settings: &Settings,
+    url: String,
+    retries: u32,
+    retry_delay: u64,
+) -> Result<profile::api::Config, Box<dyn Error>> {
+    let Some(api_key) = settings.api_key().map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?
+    else {
+        log::error!("No API key. Use --api-key, SOFTSIM_API_KEY or `softsim configure`");
+        return Err("No API key".into());
+    };
 
-    Ok(())
+    Ok(profile::api::Config {
+        api_key,
+        url,
+        retries,
+        retry_delay: std::time::Duration::from_millis(retry_delay),
+    })
 }
 
-fn read_and_decrypt(
-    path: &PathBuf,
+fn decrypt(
+    encrypted_profile: &profile::EncryptedProfile,
     key: &profile::crypto::Key,
 ) -> Result<profile::Profile, Box<dyn Error>> {
-    let file = std::fs::File::open(path)?;
-    let reader = std::io::BufReader::new(file);
-    let encrypted_profile: models::profile::EncryptedProfile = serde_json::from_reader(reader)
-        .map_err(|e| format!("Failed to parse encrypted profile. Is the file corrupted? {e}"))?;
-
     let mut profile = key
-        .decrypt(encrypted_profile.profile())
+        .decrypt(encrypted_profile)
         .map_err(|e| format!("Failed to decrypt profile. Is the key correct? {e}"))?;
     if profile.iccid.is_none() {
         profile.iccid = Some(encrypted_profile.iccid().clone());
@@ -156,84 +297,964 @@ async fn fetch_profiles(
     }
     let mut profiles_json = store_at.clone();
     profiles_json.push("profiles.json");
-    // fail early if file exists
-    let mut file = match std::fs::File::options()
-        .create_new(true)
-        .write(true)
-        .open(&profiles_json)
-    {
-        Ok(f) => f,
-        Err(e) => {
-            log::debug!("Failed to open file: {}", e);
-            if e.kind() == std::io::ErrorKind::AlreadyExists {
+
+    let mut checkpoint = match Checkpoint::load(store_at)? {
+        Some(c) => {
+            log::info!(
+                "Resuming fetch. {} of {} profiles are already stored",
+                c.fetched,
+                profile_count
+            );
+            if c.url != api_config.url {
+                log::warn!(
+                    "Previous fetch used {}. Continuing with {}",
+                    c.url,
+                    api_config.url
+                );
+            }
+            c
+        }
+        None => {
+            // fail early if file exists
+            if profiles_json.exists() {
                 log::error!(
                     "File already exists at {}. ss_cli won't overwrite existing files.",
                     store_at.display()
                 );
                 return Err("".into());
             }
-            return Err(e.into());
+            Checkpoint {
+                url: api_config.url.clone(),
+                ..Default::default()
+            }
         }
     };
+    checkpoint.requested = profile_count;
 
-    let profiles = models::profile::api::get(api_config, profile_count).await;
-    match profiles {
-        Ok(p) => {
-            let json = serde_json::to_string(&p)?;
+    // a fetch that stopped between writing a batch and saving the checkpoint
+    // left the batch behind. Requesting it again would overwrite it.
+    for name in checkpoint.unrecorded_batches(store_at)? {
+        let content = std::fs::read_to_string(store_at.join(&name).with_extension("json"))?;
+        let batch: Vec<models::profile::EncryptedProfile> = serde_json::from_str(&content)
+            .map_err(|e| format!("Failed to parse batch {}. Is the file corrupted? {e}", name))?;
+        for profile in &batch {
+            fs::store(profile, store_at, profile.iccid(), "json")?;
+        }
+        log::warn!(
+            "Recovered {} profiles from {}, which the checkpoint didn't record",
+            batch.len(),
+            name
+        );
+        checkpoint.fetched += batch.len() as u32;
+        checkpoint.batches.push(name);
+        checkpoint.save(store_at)?;
+    }
 
-            file.write_all(json.as_bytes())?;
+    let remaining = profile_count.saturating_sub(checkpoint.fetched);
+    if remaining > 0 {
+        let store_at: &std::path::Path = store_at;
+        let res = models::profile::api::get(api_config, remaining, |batch| {
+            // the batch file is the record of what was fetched. Per-ICCID files
+            // and the checkpoint are only written once it is on disk.
+            let name = checkpoint.next_batch();
+            fs::write_atomic(
+                &store_at.join(&name).with_extension("json"),
+                serde_json::to_string(&batch)?.as_bytes(),
+            )?;
+            for profile in &batch {
+                fs::store(profile, store_at, profile.iccid(), "json")?;
+            }
 
-            drop(file);
+            checkpoint.fetched += batch.len() as u32;
+            checkpoint.batches.push(name);
+            checkpoint.save(store_at)
+        })
+        .await;
 
-            p.into_iter().for_each(|profile| {
-                fs::store(&profile, store_at, profile.iccid(), "json").unwrap();
-            });
+        if let Err(e) = res {
+            if checkpoint.fetched > 0 {
+                log::info!(
+                    "Stored {} of {} profiles. Run the same command again to fetch the rest.",
+                    checkpoint.fetched,
+                    profile_count
+                );
+            }
+            return Err(e);
+        }
+    }
 
-            log::info!("Stored profiles in: {}", store_at.display());
-            Ok(())
+    // all batches are on disk. Merge them into a single profiles.json.
+    // With a checkpoint left, an existing profiles.json is a merge that
+    // stopped before cleaning up, so only the cleanup is left to do.
+    if !profiles_json.exists() {
+        let mut profiles: Vec<models::profile::EncryptedProfile> = Vec::new();
+        for batch in &checkpoint.batches {
+            let path = store_at.join(batch).with_extension("json");
+            let content = std::fs::read_to_string(&path)?;
+            profiles.extend(serde_json::from_str::<Vec<_>>(&content)?);
         }
-        Err(e) => {
-            log::info!("Removing file: {}", profiles_json.display());
-            std::fs::remove_file(&profiles_json)?;
-            Err(e)
+        fs::write_atomic(&profiles_json, serde_json::to_string(&profiles)?.as_bytes())?;
+    }
+
+    for batch in &checkpoint.batches {
+        match std::fs::remove_file(store_at.join(batch).with_extension("json")) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
         }
     }
+    Checkpoint::remove(store_at)?;
+
+    log::info!("Stored profiles in: {}", store_at.display());
+    Ok(())
 }
 
 fn next(
-    key_path: &PathBuf,
-    base_path: &PathBuf,
-    format: config::Format,
-    smsp: bool,
-    smsc: bool,
+    key: &config::KeyArgs,
+    base_path: &std::path::Path,
+    count: usize,
+    reserve: &config::ReserveArgs,
+    serial: Option<String>,
+    device_id: Option<String>,
+    output: &config::OutputArgs,
 ) -> Result<(), Box<dyn Error>> {
-    let key = match models::profile::crypto::Key::new(key_path) {
-        Ok(k) => k,
+    // parse the key once, no matter how many profiles are handed out
+    let key = match models::profile::crypto::Key::new(&key.key, &key.passphrase()) {
+        Ok(k) => k.with_padding(key.padding),
         Err(e) => {
             log::debug!("Failed to load key: {}", e);
             return Err(e);
         }
     };
 
-    let profile_path = get_next(base_path)?;
-    log::debug!("Next profile: {}", profile_path.path().display());
-    let profile = read_and_decrypt(&profile_path.path(), &key)?;
-    mark_exported(&profile_path)?;
+    let store = store::open(base_path)?;
+    let store = store.as_ref();
 
-    match format {
-        config::Format::Hex => {
-            std::io::stdout().write_all(profile.to_hex(smsp, smsc).as_bytes())?;
+    let claims = match &device_id {
+        // --device-id can't be combined with --count, there is exactly one profile
+        Some(device) => match pool::claim_for(store, device)? {
+            pool::Claim::Bound(iccid) => {
+                return reissue(&key, store, &iccid, serial, device, reserve, output)
+            }
+            pool::Claim::New(claim) => vec![claim],
+        },
+        None => pool::claim(store, count)?,
+    };
+    let mut profiles = Vec::with_capacity(claims.len());
+    for (i, claim) in claims.iter().enumerate() {
+        log::debug!("Next profile: {}", claim.iccid());
+        let profile = decrypt(claim, &key).and_then(|p| {
+            p.validate().map_err(|e| {
+                log::error!("{}: {}", claim.iccid(), e);
+                e
+            })?;
+            Ok(p)
+        });
+        match profile {
+            Ok(p) => profiles.push(p),
+            Err(e) => {
+                pool::quarantine(store, claim.iccid(), &e.to_string())?;
+                // the others are fine, as far as we know
+                pool::rollback(store, &claims[..i])?;
+                pool::rollback(store, &claims[i + 1..])?;
+                return Err(e);
+            }
         }
+    }
+    let iccids: Vec<String> = claims.iter().map(|c| c.iccid().clone()).collect();
+
+    let mut token = None;
+    if let Some(ttl) = reserve.ttl() {
+        let reservation = match Reservation::create(store.dir(), iccids.clone(), ttl) {
+            Ok(r) => r,
+            Err(e) => {
+                pool::rollback(store, &claims)?;
+                return Err(e);
+            }
+        };
+        log::debug!(
+            "Reserved {} until {}",
+            reservation.iccids.join(", "),
+            reservation.expires
+        );
+        token = Some(reservation.token);
+    }
 
-        config::Format::Json => {
-            std::io::stdout().write_all(profile.to_json(smsp, smsc)?.as_bytes())?;
+    // no profile leaves without a record of it
+    let mut events = Vec::with_capacity(profiles.len());
+    for (iccid, profile) in iccids.iter().zip(&profiles) {
+        if let Some(imsi) = &profile.imsi {
+            store.record_imsi(iccid, imsi)?;
         }
+        events.push(audit::Event::Issue {
+            iccid: iccid.clone(),
+            imsi: profile.imsi.clone(),
+            serial: serial.clone(),
+            device_id: device_id.clone(),
+            format: format_name(output.format),
+            token: token.clone(),
+        });
+    }
+    if let Err(e) = record(store, events) {
+        log::error!("Failed to write audit log: {}", e);
+        give_back(store, &iccids, token.as_deref())?;
+        return Err(e);
+    }
+
+    if let Some(token) = &token {
+        if let Err(e) = write_token(token, reserve) {
+            log::error!("Failed to write reservation token: {}", e);
+            give_back(store, &iccids, Some(token))?;
+            return Err(e);
+        }
+    }
+    write_profiles(&profiles, output)
+}
+
+/// Hand out the profile a device already got again, instead of a new one.
+fn reissue(
+    key: &profile::crypto::Key,
+    store: &dyn Store,
+    iccid: &str,
+    serial: Option<String>,
+    device_id: &str,
+    reserve: &config::ReserveArgs,
+    output: &config::OutputArgs,
+) -> Result<(), Box<dyn Error>> {
+    log::debug!("Device {} already has profile {}", device_id, iccid);
+    let in_use = matches!(
+        store.state(iccid)?,
+        Some(State::Consumed | State::Provisioned)
+    );
+    let Some(encrypted) = store.get(iccid)?.filter(|_| in_use) else {
+        log::error!(
+            "Profile {} bound to device {} is not in use. Is the profile directory intact?",
+            iccid,
+            device_id
+        );
+        return Err("Bound profile not found".into());
+    };
+    let profile = decrypt(&encrypted, key)?;
+
+    let token = reservation::token_of(store.dir(), iccid)?;
 
-        config::Format::Raw => {
-            let str_profile = serde_json::to_string(&profile)?;
+    record(
+        store,
+        vec![audit::Event::Issue {
+            iccid: iccid.to_string(),
+            imsi: profile.imsi.clone(),
+            serial,
+            device_id: Some(device_id.to_string()),
+            format: format_name(output.format),
+            token: token.clone(),
+        }],
+    )?;
 
-            std::io::stdout().write_all(str_profile.as_bytes())?;
+    if let Some(token) = &token {
+        write_token(token, reserve)?;
+    }
+    write_profiles(&[profile], output)
+}
+
+/// Append to the audit log of the pool, under its lock.
+fn record(store: &dyn Store, events: Vec<audit::Event>) -> Result<(), Box<dyn Error>> {
+    let lock = pool::Lock::acquire(store.dir())?;
+    events
+        .into_iter()
+        .try_for_each(|event| audit::append(store.dir(), event, &lock))
+}
+
+/// Hand the reservation token to the caller, in `--token-file` or on stderr.
+/// stdout is reserved for the profiles.
+fn write_token(token: &str, reserve: &config::ReserveArgs) -> Result<(), Box<dyn Error>> {
+    match &reserve.token_file {
+        Some(path) => fs::write_atomic(path, format!("{}\n", token).as_bytes()),
+        None => {
+            eprintln!("{}", token);
+            Ok(())
+        }
+    }
+}
+
+/// Undo a `next` that failed after its profiles were claimed.
+fn give_back(
+    store: &dyn Store,
+    iccids: &[String],
+    token: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    match token {
+        Some(token) => reservation::abort(store, token).map(|_| ()),
+        // drops the device binding too, if one was made
+        None => {
+            let lock = pool::Lock::acquire(store.dir())?;
+            iccids
+                .iter()
+                .try_for_each(|iccid| pool::unclaim(store, iccid, &lock))
         }
     }
+}
+
+fn format_name(format: config::Format) -> String {
+    format.to_possible_value().unwrap().get_name().to_string()
+}
+
+fn write_profiles(
+    profiles: &[profile::Profile],
+    output: &config::OutputArgs,
+) -> Result<(), Box<dyn Error>> {
+    let (smsp, smsc) = (output.smsp, !output.no_smsc);
+    let mut encoded = Vec::with_capacity(profiles.len());
+    for profile in profiles {
+        encoded.push(match output.format {
+            config::Format::Hex => profile.to_hex(smsp, smsc),
+            config::Format::Json => profile.to_json(smsp, smsc)?,
+            config::Format::Raw if output.show_secrets => profile.to_raw_json()?,
+            config::Format::Raw => profile.masked().to_raw_json()?,
+        });
+    }
+
+    // a single profile is written as is, without a trailing newline
+    let (open, separator, close) = if encoded.len() == 1 {
+        ("", "", "")
+    } else if output.format == config::Format::Hex || output.lines {
+        ("", "\n", "\n")
+    } else {
+        ("[", ",", "]")
+    };
+    // sized up front, a growing buffer would leave copies of the profiles behind
+    let size = open.len()
+        + close.len()
+        + encoded
+            .iter()
+            .map(|e| e.len() + separator.len())
+            .sum::<usize>();
+    let mut out = Zeroizing::new(String::with_capacity(size));
+    out.push_str(open);
+    for (i, profile) in encoded.iter().enumerate() {
+        if i > 0 {
+            out.push_str(separator);
+        }
+        out.push_str(profile);
+    }
+    out.push_str(close);
+    std::io::stdout().write_all(out.as_bytes())?;
+
+    Ok(())
+}
+
+fn import(file: &std::path::Path, out: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    if store::is_database(out) {
+        log::error!("Import into a profile directory, then use `migrate` to add it to a database");
+        return Err("Can't import into a database".into());
+    }
+
+    let profiles = bundle::read(file).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    log::info!(
+        "Importing {} profiles from {}",
+        profiles.len(),
+        file.display()
+    );
+
+    if !out.is_dir() {
+        log::debug!("Creating directory {}", out.display());
+        std::fs::create_dir_all(out)?;
+    }
+    let store = store::dir::DirStore::new(out);
+    // no `next` or `split` may touch the pool between the merge and the writes
+    let _lock = pool::Lock::acquire(out)?;
+    let merge = bundle::merge(&store, profiles).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+
+    if !merge.conflicts.is_empty() {
+        for iccid in &merge.conflicts {
+            log::error!(
+                "Profile {} differs from the copy in {} or earlier in {}",
+                iccid,
+                out.display(),
+                file.display()
+            );
+        }
+        log::error!(
+            "Nothing was imported. Find out which of the conflicting profiles is right first"
+        );
+        return Err("Conflicting profiles".into());
+    }
+
+    for profile in &merge.new {
+        let path = out.join(format!("{}.json", profile.iccid()));
+        fs::write_atomic(&path, serde_json::to_string(profile)?.as_bytes())?;
+    }
+
+    writeln!(
+        std::io::stdout(),
+        "imported: {}\nduplicates: {}",
+        merge.new.len(),
+        merge.duplicates.len()
+    )?;
+    Ok(())
+}
+
+fn keygen(bits: usize, out: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    let mut public_path = out.as_os_str().to_owned();
+    public_path.push(".pub");
+    let public_path = PathBuf::from(public_path);
+
+    for path in [out, public_path.as_path()] {
+        if path.exists() {
+            log::error!(
+                "File already exists at {}. ss_cli won't overwrite existing files.",
+                path.display()
+            );
+            return Err("".into());
+        }
+    }
+
+    log::info!("Generating {} bit key pair", bits);
+    let key = profile::crypto::Key::generate(bits)?;
+
+    // create_new, in case either file appeared while the key was generated
+    let mut options = std::fs::File::options();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut private = options.open(out)?;
+    let mut public = match std::fs::File::options()
+        .create_new(true)
+        .write(true)
+        .open(&public_path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            drop(private);
+            let _ = std::fs::remove_file(out);
+            return Err(e.into());
+        }
+    };
+    private.write_all(key.private_key_pem()?.as_bytes())?;
+    public.write_all(key.public_key_pem()?.as_bytes())?;
+
+    log::info!("Private key: {}", out.display());
+    log::info!("Public key: {}", public_path.display());
+    writeln!(std::io::stdout(), "{}", key.fingerprint()?)?;
+
+    Ok(())
+}
+
+fn status(base_path: &std::path::Path, min_available: Option<usize>) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let inventory = store.inventory()?;
+    let reserved: usize = reservation::all(store.dir())?
+        .iter()
+        .map(|r| r.iccids.len())
+        .sum();
+
+    let mut out = format!(
+        "total: {}\navailable: {}\nconsumed: {}\nreserved: {}\n",
+        inventory.total(),
+        inventory.available.len(),
+        inventory.consumed.len(),
+        reserved
+    );
+    for (name, iccids) in [
+        ("available", &inventory.available),
+        ("consumed", &inventory.consumed),
+    ] {
+        out.push_str(&format!("{} ranges:\n", name));
+        for (first, last, count) in pool::ranges(iccids) {
+            out.push_str(&format!("  {} - {} ({})\n", first, last, count));
+        }
+    }
+    std::io::stdout().write_all(out.as_bytes())?;
+
+    if let Some(minimum) = min_available {
+        if inventory.available.len() < minimum {
+            let err = pool::LowInventory {
+                available: inventory.available.len(),
+                minimum,
+            };
+            log::warn!("{}", err);
+            return Err(err.into());
+        }
+    }
+
+    Ok(())
+}
+
+fn release(
+    base_path: &std::path::Path,
+    iccid: &str,
+    reason: String,
+    by: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let by = by.unwrap_or_else(audit::username);
+    pool::release(store.as_ref(), iccid, &by, &reason).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+
+    log::info!("Released profile {}", iccid);
+    Ok(())
+}
+
+fn confirm(
+    base_path: &std::path::Path,
+    iccid: &str,
+    by: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let by = by.unwrap_or_else(audit::username);
+    pool::confirm(store.as_ref(), iccid, &by).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+
+    log::info!("Confirmed profile {}", iccid);
+    Ok(())
+}
+
+fn commit(base_path: &std::path::Path, token: &str) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let reservation = reservation::commit(store.as_ref(), token).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    log::info!("Committed {}", reservation.iccids.join(", "));
+    Ok(())
+}
+
+fn abort(base_path: &std::path::Path, token: &str) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let reservation = reservation::abort(store.as_ref(), token).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    log::info!("Returned {} to the pool", reservation.iccids.join(", "));
+    Ok(())
+}
+
+fn rekey(
+    old_key: &std::path::Path,
+    passphrase: &Secret,
+    padding: profile::crypto::Padding,
+    new_pubkey: &std::path::Path,
+    new_padding: profile::crypto::Padding,
+    base_path: &std::path::Path,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let old = profile::crypto::Key::new(old_key, passphrase)
+        .map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?
+        .with_padding(padding);
+    let new = profile::crypto::PublicKey::new(new_pubkey).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    let (old_fingerprint, new_fingerprint) = (old.fingerprint()?, new.fingerprint()?);
+    if old_fingerprint == new_fingerprint {
+        log::error!("The new public key belongs to the old key");
+        return Err("Same key".into());
+    }
+    log::info!("Rekeying from {} to {}", old_fingerprint, new_fingerprint);
+
+    let store = store::open(base_path)?;
+    // nothing is handed out while profiles change key
+    let lock = pool::Lock::acquire(store.dir())?;
+    let rekeyed = rekey::reencrypt(store.as_ref(), &old, &new, new_padding, &lock)?;
+
+    let mut out = std::io::stdout();
+    if !rekeyed.failed.is_empty() {
+        for (iccid, reason) in &rekeyed.failed {
+            writeln!(out, "{}: {}", iccid, reason)?;
+        }
+        log::error!(
+            "{} of {} profiles can't be re-encrypted. Nothing was changed",
+            rekeyed.failed.len(),
+            rekeyed.failed.len() + rekeyed.profiles.len()
+        );
+        return Err("Rekey failed".into());
+    }
+
+    if !rekeyed.current.is_empty() {
+        log::info!(
+            "{} profiles are already encrypted to {}",
+            rekeyed.current.len(),
+            new_fingerprint
+        );
+    }
+    if dry_run {
+        writeln!(out, "would rekey: {}", rekeyed.profiles.len())?;
+        return Ok(());
+    }
+    if rekeyed.profiles.is_empty() {
+        writeln!(out, "rekeyed: 0")?;
+        return Ok(());
+    }
+
+    rekey::apply(store.as_ref(), &rekeyed.profiles, &lock).map_err(|e| {
+        log::error!("{}. Nothing was changed", e);
+        e
+    })?;
+    audit::append(
+        store.dir(),
+        audit::Event::Rekey {
+            iccids: rekeyed.profiles.iter().map(|p| p.iccid().clone()).collect(),
+            old_key: old_fingerprint,
+            new_key: new_fingerprint,
+            by: audit::username(),
+        },
+        &lock,
+    )?;
+    writeln!(out, "rekeyed: {}", rekeyed.profiles.len())?;
+
+    if !store::is_database(base_path) && base_path.join("profiles.json").exists() {
+        log::warn!(
+            "{} still holds the profiles encrypted to the old key",
+            base_path.join("profiles.json").display()
+        );
+    }
+    Ok(())
+}
+
+fn split(
+    base_path: &std::path::Path,
+    parts: Option<u32>,
+    sizes: Option<Vec<u64>>,
+    out: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    if store::is_database(base_path) {
+        log::error!("Only profile directories can be split");
+        return Err("Can't split a database".into());
+    }
+
+    let sizes = match (parts, sizes) {
+        (_, Some(sizes)) => split::Sizes::Given(sizes.into_iter().map(|s| s as usize).collect()),
+        (Some(parts), None) => split::Sizes::Equal(parts as usize),
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+    let out = out.as_deref().unwrap_or(base_path);
+
+    let manifests = split::split(base_path, out, &sizes).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+
+    let mut stdout = std::io::stdout();
+    for manifest in &manifests {
+        writeln!(stdout, "{}: {}", manifest.part, manifest.count)?;
+        for range in &manifest.ranges {
+            writeln!(
+                stdout,
+                "  {} - {} ({})",
+                range.first, range.last, range.count
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn lookup(
+    base_path: &std::path::Path,
+    iccid: Option<String>,
+    device_id: Option<String>,
+    imsi: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let found = match (&iccid, &device_id, &imsi) {
+        (Some(iccid), _, _) => binding::device(store.dir(), iccid)?
+            .ok_or_else(|| format!("Profile {} is not bound to a device", iccid)),
+        (_, Some(device), _) => binding::iccid(store.dir(), device)?
+            .ok_or_else(|| format!("Device {} has no profile", device)),
+        (_, _, Some(imsi)) => store
+            .find_imsi(imsi)?
+            .ok_or_else(|| format!("No profile with IMSI {} has been handed out", imsi)),
+        (None, None, None) => unreachable!("clap requires one of them"),
+    };
+
+    match found {
+        Ok(found) => {
+            writeln!(std::io::stdout(), "{}", found)?;
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            Err(e.into())
+        }
+    }
+}
+
+async fn reconcile(
+    base_path: &std::path::Path,
+    active: Option<&std::path::Path>,
+    api_config: Option<&profile::api::Config>,
+) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let inventory = store.inventory()?;
+    let issued = reconcile::issued(&audit::read(store.dir())?);
+
+    let active = match (active, api_config) {
+        (Some(path), _) => reconcile::read_active(path).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?,
+        (None, Some(config)) => profile::api::active_iccids(config).await?,
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+
+    let report = reconcile::compare(&inventory, &issued, &active);
+    let mut out = std::io::stdout();
+    for (title, iccids) in [
+        ("used, not active", &report.used_not_active),
+        ("active, not used", &report.active_not_used),
+    ] {
+        writeln!(out, "{}: {}", title, iccids.len())?;
+        for iccid in iccids {
+            writeln!(out, "  {}", iccid)?;
+        }
+    }
+    writeln!(out, "never used: {}", report.never_used.len())?;
+    for (first, last, count) in pool::ranges(&report.never_used) {
+        writeln!(out, "  {} - {} ({})", first, last, count)?;
+    }
+    writeln!(
+        out,
+        "matched: {}\nnot in this pool: {}",
+        report.matched, report.unknown
+    )?;
+
+    if report.discrepancies() > 0 {
+        log::error!(
+            "{} profiles differ between the pool and the platform",
+            report.discrepancies()
+        );
+        return Err("Reconciliation found discrepancies".into());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn migrate(from: &std::path::Path, to: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    if !store::is_database(to) {
+        log::error!("{} must end in .db, .sqlite or .sqlite3", to.display());
+        return Err("Not a database path".into());
+    }
+
+    // nothing is handed out from the directory while it is copied
+    let _lock = pool::Lock::acquire(from)?;
+    let source = store::dir::DirStore::new(from);
+    let db = store::sqlite::SqliteStore::create(to)?;
+    let added = db.import(&source)?;
+    log::info!("Imported {} profiles into {}", added, to.display());
+
+    if std::fs::canonicalize(db.dir())? != std::fs::canonicalize(from)? {
+        log::warn!(
+            "Reservations, device bindings and the audit log are kept next to the database. Copy them from {} if needed",
+            from.display()
+        );
+    }
+    log::warn!(
+        "Use --in {} from now on. Profiles handed out from {} are no longer tracked in the database",
+        to.display(),
+        from.display()
+    );
+    Ok(())
+}
+
+fn audit_export(
+    base_path: &std::path::Path,
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+    out: Option<&std::path::Path>,
+) -> Result<(), Box<dyn Error>> {
+    let store = store::open(base_path)?;
+    let records: Vec<audit::Record> = audit::read(store.dir())?
+        .into_iter()
+        .filter(|r| {
+            let day = r.timestamp.date_naive();
+            from.is_none_or(|from| day >= from) && to.is_none_or(|to| day <= to)
+        })
+        .collect();
+    log::info!("Exporting {} audit records", records.len());
+
+    match out {
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            audit::to_csv(&records, &mut file)?;
+            file.flush()?;
+        }
+        None => audit::to_csv(&records, &mut std::io::stdout().lock())?,
+    }
+
+    Ok(())
+}
+
+fn verify(
+    key: &config::KeyArgs,
+    base_path: &std::path::Path,
+    sample: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let key = profile::crypto::Key::new(&key.key, &key.passphrase())?.with_padding(key.padding);
+
+    let store = store::open(base_path)?;
+    let mut iccids = store.inventory()?.available;
+    if let Some(n) = sample {
+        // partial Fisher-Yates shuffle, the first n entries are the sample
+        use rsa::rand_core::{OsRng, RngCore};
+        let n = n.min(iccids.len());
+        for i in 0..n {
+            let j = i + (OsRng.next_u64() % (iccids.len() - i) as u64) as usize;
+            iccids.swap(i, j);
+        }
+        iccids.truncate(n);
+        iccids.sort();
+    }
+    log::info!(
+        "Verifying {} profiles in {}",
+        iccids.len(),
+        base_path.display()
+    );
+
+    let mut failed = 0;
+    let mut out = std::io::stdout();
+    for iccid in &iccids {
+        let mut problems = Vec::new();
+
+        match store.get(iccid) {
+            Err(e) => problems.push(e.to_string()),
+            Ok(None) => problems.push(String::from("not found")),
+            Ok(Some(encrypted)) => match key.decrypt(&encrypted) {
+                Err(e) => problems.push(format!("undecryptable: {}", e)),
+                Ok(profile) => {
+                    if let Some(inner) = &profile.iccid {
+                        if inner != encrypted.iccid() {
+                            problems.push(format!(
+                                "ICCID mismatch: envelope {} profile {}",
+                                encrypted.iccid(),
+                                inner
+                            ));
+                        }
+                    }
+                    if let Err(e) = profile.validate() {
+                        problems.push(format!("invalid: {}", e.problems.join(", ")));
+                    }
+                    let missing = profile.missing_fields();
+                    if !missing.is_empty() {
+                        problems.push(format!("missing fields: {}", missing.join(", ")));
+                    }
+                }
+            },
+        }
+
+        if !problems.is_empty() {
+            failed += 1;
+            writeln!(out, "{}: {}", iccid, problems.join("; "))?;
+        }
+    }
+
+    writeln!(
+        out,
+        "verified: {}\nok: {}\nfailed: {}",
+        iccids.len(),
+        iccids.len() - failed,
+        failed
+    )?;
+
+    if failed > 0 {
+        log::error!(
+            "{} of {} profiles failed verification",
+            failed,
+            iccids.len()
+        );
+        return Err("Verification failed".into());
+    }
+    Ok(())
+}
+
+fn configure(
+    config: Option<&std::path::Path>,
+    profile: Option<&str>,
+    mut changes: Settings,
+    api_key_stdin: bool,
+) -> Result<(), Box<dyn Error>> {
+    let Some(path) = settings::path(config) else {
+        log::error!("No config directory found. Use --config");
+        return Err("No config directory".into());
+    };
+    let mut file = if path.exists() {
+        settings::ConfigFile::load(&path).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?
+    } else {
+        settings::ConfigFile::default()
+    };
+
+    let profile = settings::profile_name(profile);
+    if api_key_stdin {
+        let stdin = Secret::File(PathBuf::from("-"));
+        changes.api_key = Some(stdin.read("API key")?.to_string());
+    }
+
+    if changes == Settings::default() {
+        let settings = file.settings(profile.as_deref()).map_err(|e| {
+            log::error!("{}", e);
+            e
+        })?;
+        let mut out = std::io::stdout();
+        writeln!(out, "config: {}", path.display())?;
+        writeln!(out, "profiles_path: {}", settings.profiles_path().display())?;
+        writeln!(
+            out,
+            "url: {}",
+            settings.url.as_deref().unwrap_or(profile::api::DEFAULT_URL)
+        )?;
+        let api_key = match (&settings.api_key, settings.api_key_source()) {
+            (Some(key), _) => format!("{} (stored in the config file)", mask(key)),
+            (None, Some(source)) => format!("read from {}", source),
+            (None, None) => String::from("not set"),
+        };
+        writeln!(out, "api_key: {}", api_key)?;
+        return Ok(());
+    }
+
+    let target = match profile {
+        Some(name) => file.profiles.entry(name).or_default(),
+        None => &mut file.defaults,
+    };
+    // a new key file replaces a stored key and the other way around
+    *target = changes.or(target.clone());
+    file.save(&path)?;
+
+    log::info!("Updated {}", path.display());
+    Ok(())
+}
+
+fn decode(hex: Option<String>, show_secrets: bool) -> Result<(), Box<dyn Error>> {
+    let hex = Zeroizing::new(match hex {
+        Some(h) => h,
+        None => std::io::read_to_string(std::io::stdin())?,
+    });
+
+    let profile = profile::Profile::from_hex(&hex).map_err(|e| {
+        log::error!("Failed to decode profile: {}", e);
+        e
+    })?;
+    profile.validate().map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    let str_profile = if show_secrets {
+        profile.to_raw_json()?
+    } else {
+        profile.masked().to_raw_json()?
+    };
+    std::io::stdout().write_all(str_profile.as_bytes())?;
+
     Ok(())
 }
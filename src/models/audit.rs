@@ -0,0 +1,392 @@
+use super::pool::Lock;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Append-only log of everything done to a profile pool, one JSON record per line.
+///
+/// The extension keeps it out of the way of `store::dir::is_available`.
+pub const AUDIT_FILE: &str = "audit.jsonl";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub host: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A profile was handed out by `next`. Never holds any of the secrets.
+    Issue {
+        iccid: String,
+        imsi: Option<String>,
+        serial: Option<String>,
+        device_id: Option<String>,
+        format: String,
+        token: Option<String>,
+    },
+    /// A consumed profile was returned to the pool.
+    Release {
+        iccid: String,
+        by: String,
+        reason: String,
+    },
+    /// A consumed profile was confirmed as written to a device.
+    Confirm { iccid: String, by: String },
+    /// Reserved profiles were confirmed as written to a device.
+    Commit { token: String, iccids: Vec<String> },
+    /// Reserved profiles were returned to the pool.
+    Abort { token: String, iccids: Vec<String> },
+    /// Reserved profiles were returned to the pool after the reservation timed out.
+    Expire { token: String, iccids: Vec<String> },
+    /// A handed out profile that can't be used was moved out of the pool.
+    Quarantine { iccid: String, reason: String },
+    /// Profiles were encrypted to a new key. Keys are given by fingerprint.
+    Rekey {
+        iccids: Vec<String>,
+        old_key: String,
+        new_key: String,
+        by: String,
+    },
+}
+
+impl Record {
+    pub fn new(event: Event) -> Record {
+        Record {
+            timestamp: chrono::Utc::now(),
+            host: hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            event,
+        }
+    }
+}
+
+pub fn path(dir: &Path) -> PathBuf {
+    dir.join(AUDIT_FILE)
+}
+
+/// Append `event` to the audit log of the pool in `dir`. Callers hold the
+/// pool lock: an append on NFS is a seek and a write, so lines of two
+/// stations could otherwise overwrite each other.
+pub fn append(dir: &Path, event: Event, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+    let mut line = serde_json::to_string(&Record::new(event))?;
+    line.push('\n');
+
+    // a single write of a whole line
+    let mut file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path(dir))
+        .map_err(|e| format!("Failed to open audit log in {}: {}", dir.display(), e))?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// All records in the audit log of a profile directory, oldest first.
+///
+/// A line that can't be parsed, like one cut short by a full disk, is logged
+/// and skipped so the rest of the log stays readable.
+pub fn read(dir: &Path) -> Result<Vec<Record>, Box<dyn Error>> {
+    let content = match std::fs::read_to_string(path(dir)) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let records = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(i, line)| match serde_json::from_str(line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                log::error!("Skipping {} line {}: {}", AUDIT_FILE, i + 1, e);
+                None
+            }
+        })
+        .collect();
+
+    Ok(records)
+}
+
+const CSV_HEADER: [&str; 12] = [
+    "timestamp",
+    "event",
+    "iccid",
+    "imsi",
+    "serial",
+    "device_id",
+    "format",
+    "token",
+    "by",
+    "reason",
+    "host",
+    "version",
+];
+
+/// The CSV columns of an event. What an event doesn't have is left empty.
+#[derive(Default)]
+struct Row<'a> {
+    event: &'a str,
+    iccids: Vec<&'a str>,
+    imsi: Option<&'a str>,
+    serial: Option<&'a str>,
+    device_id: Option<&'a str>,
+    format: Option<&'a str>,
+    token: Option<&'a str>,
+    by: Option<&'a str>,
+    reason: Option<&'a str>,
+}
+
+impl<'a> From<&'a Event> for Row<'a> {
+    fn from(event: &'a Event) -> Self {
+        let all = |iccids: &'a [String]| iccids.iter().map(String::as_str).collect();
+        match event {
+            Event::Issue {
+                iccid,
+                imsi,
+                serial,
+                device_id,
+                format,
+                token,
+            } => Row {
+                event: "issue",
+                iccids: vec![iccid],
+                imsi: imsi.as_deref(),
+                serial: serial.as_deref(),
+                device_id: device_id.as_deref(),
+                format: Some(format),
+                token: token.as_deref(),
+                ..Default::default()
+            },
+            Event::Release { iccid, by, reason } => Row {
+                event: "release",
+                iccids: vec![iccid],
+                by: Some(by),
+                reason: Some(reason),
+                ..Default::default()
+            },
+            Event::Confirm { iccid, by } => Row {
+                event: "confirm",
+                iccids: vec![iccid],
+                by: Some(by),
+                ..Default::default()
+            },
+            Event::Commit { token, iccids } => Row {
+                event: "commit",
+                iccids: all(iccids),
+                token: Some(token),
+                ..Default::default()
+            },
+            Event::Abort { token, iccids } => Row {
+                event: "abort",
+                iccids: all(iccids),
+                token: Some(token),
+                ..Default::default()
+            },
+            Event::Expire { token, iccids } => Row {
+                event: "expire",
+                iccids: all(iccids),
+                token: Some(token),
+                ..Default::default()
+            },
+            Event::Quarantine { iccid, reason } => Row {
+                event: "quarantine",
+                iccids: vec![iccid],
+                reason: Some(reason),
+                ..Default::default()
+            },
+            Event::Rekey { iccids, by, .. } => Row {
+                event: "rekey",
+                iccids: all(iccids),
+                by: Some(by),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Write records as CSV, one row per ICCID. Events that cover several
+/// profiles, like a commit, are split into a row for each of them.
+pub fn to_csv(records: &[Record], out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    writeln!(out, "{}", CSV_HEADER.join(","))?;
+
+    for record in records {
+        let timestamp = record.timestamp.to_rfc3339();
+        let row = Row::from(&record.event);
+        for iccid in &row.iccids {
+            let fields = [
+                Some(timestamp.as_str()),
+                Some(row.event),
+                Some(iccid),
+                row.imsi,
+                row.serial,
+                row.device_id,
+                row.format,
+                row.token,
+                row.by,
+                row.reason,
+                Some(&record.host),
+                Some(&record.version),
+            ];
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|f| csv_field(&defuse(f.unwrap_or(""))))
+                .collect();
+            writeln!(out, "{}", fields.join(","))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep a spreadsheet from running a value as a formula. Every field goes
+/// through here, since serials, device ids and hostnames are free text too.
+fn defuse(s: &str) -> String {
+    if s.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", s)
+    } else {
+        s.to_string()
+    }
+}
+
+/// Quote a field if it contains anything that would break the row, per RFC 4180.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// The operator running the command, as far as the OS knows.
+pub fn username() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_what_was_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lock::acquire(dir.path()).unwrap();
+        assert!(read(dir.path()).unwrap().is_empty());
+
+        append(
+            dir.path(),
+            Event::Issue {
+                iccid: String::from("89457300000013500452"),
+                imsi: Some(String::from("234602102350049")),
+                serial: Some(String::from("SN-1")),
+                device_id: None,
+                format: String::from("hex"),
+                token: None,
+            },
+            &lock,
+        )
+        .unwrap();
+        append(
+            dir.path(),
+            Event::Release {
+                iccid: String::from("89457300000013500452"),
+                by: String::from("alice"),
+                reason: String::from("flashing failed"),
+            },
+            &lock,
+        )
+        .unwrap();
+
+        let records = read(dir.path()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[0].event, Event::Issue { serial: Some(s), .. } if s == "SN-1"));
+        assert!(matches!(&records[1].event, Event::Release { by, .. } if by == "alice"));
+    }
+
+    #[test]
+    fn test_corrupt_line_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lock::acquire(dir.path()).unwrap();
+        let confirm = |by: &str| Event::Confirm {
+            iccid: String::from("001"),
+            by: String::from(by),
+        };
+        append(dir.path(), confirm("alice"), &lock).unwrap();
+        let mut file = std::fs::File::options()
+            .append(true)
+            .open(path(dir.path()))
+            .unwrap();
+        file.write_all(b"{\"timestamp\":\"2026-01-\n").unwrap();
+        append(dir.path(), confirm("bob"), &lock).unwrap();
+
+        let records = read(dir.path()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[1].event, Event::Confirm { by, .. } if by == "bob"));
+    }
+
+    #[test]
+    fn test_csv() {
+        let record = |event| Record {
+            timestamp: "2026-01-02T03:04:05Z".parse().unwrap(),
+            host: String::from("station-3"),
+            version: String::from("1.0.0"),
+            event,
+        };
+        let records = vec![
+            record(Event::Release {
+                iccid: String::from("001"),
+                by: String::from("bob"),
+                reason: String::from("bad board, \"rev A\""),
+            }),
+            record(Event::Release {
+                iccid: String::from("004"),
+                by: String::from("@bob"),
+                reason: String::from("=HYPERLINK(\"x\")"),
+            }),
+            record(Event::Issue {
+                iccid: String::from("005"),
+                imsi: None,
+                serial: Some(String::from("+SUM(A1)")),
+                device_id: Some(String::from("-2+3")),
+                format: String::from("json"),
+                token: None,
+            }),
+            record(Event::Release {
+                iccid: String::from("006"),
+                by: String::from("\tbob"),
+                reason: String::from("\r=1"),
+            }),
+            record(Event::Commit {
+                token: String::from("ab12"),
+                iccids: vec![String::from("002"), String::from("003")],
+            }),
+        ];
+
+        let mut out = Vec::new();
+        to_csv(&records, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "timestamp,event,iccid,imsi,serial,device_id,format,token,by,reason,host,version\n\
+             2026-01-02T03:04:05+00:00,release,001,,,,,,bob,\"bad board, \"\"rev A\"\"\",station-3,1.0.0\n\
+             2026-01-02T03:04:05+00:00,release,004,,,,,,'@bob,\"'=HYPERLINK(\"\"x\"\")\",station-3,1.0.0\n\
+             2026-01-02T03:04:05+00:00,issue,005,,'+SUM(A1),'-2+3,json,,,,station-3,1.0.0\n\
+             2026-01-02T03:04:05+00:00,release,006,,,,,,'\tbob,\"'\r=1\",station-3,1.0.0\n\
+             2026-01-02T03:04:05+00:00,commit,002,,,,,ab12,,,station-3,1.0.0\n\
+             2026-01-02T03:04:05+00:00,commit,003,,,,,ab12,,,station-3,1.0.0\n"
+        );
+    }
+}
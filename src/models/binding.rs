@@ -0,0 +1,153 @@
+use super::pool::Lock;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Which device got which profile, a JSON object from device id to ICCID.
+///
+/// Not a `.json` file, so `store::dir::is_available` doesn't take it for a profile.
+pub const BINDINGS_FILE: &str = "devices.bindings";
+
+type Bindings = BTreeMap<String, String>;
+
+fn path(base_path: &Path) -> PathBuf {
+    base_path.join(BINDINGS_FILE)
+}
+
+fn load(base_path: &Path) -> Result<Bindings, Box<dyn Error>> {
+    let path = path(base_path);
+    match std::fs::read_to_string(&path) {
+        Ok(content) => Ok(serde_json::from_str(&content).map_err(|e| {
+            format!(
+                "Failed to parse {}. Is the file corrupted? {}",
+                path.display(),
+                e
+            )
+        })?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Bindings::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(base_path: &Path, bindings: &Bindings, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+    let serialized = serde_json::to_string_pretty(bindings)?;
+    super::fs::write_atomic(&path(base_path), serialized.as_bytes())
+}
+
+/// ICCID of the profile handed out to `device_id`.
+pub fn iccid(base_path: &Path, device_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(load(base_path)?.remove(device_id))
+}
+
+/// Device the profile `iccid` was handed out to.
+pub fn device(base_path: &Path, iccid: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(load(base_path)?
+        .into_iter()
+        .find_map(|(device, i)| (i == iccid).then_some(device)))
+}
+
+/// Record that the profile `iccid` was handed out to `device_id`. A device
+/// has at most one profile and a profile at most one device.
+pub fn bind(
+    base_path: &Path,
+    device_id: &str,
+    iccid: &str,
+    lock: &Lock,
+) -> Result<(), Box<dyn Error>> {
+    let mut bindings = load(base_path)?;
+
+    if let Some(bound) = bindings.get(device_id) {
+        return Err(format!("Device {} is already bound to profile {}", device_id, bound).into());
+    }
+    if let Some((device, _)) = bindings.iter().find(|(_, i)| *i == iccid) {
+        return Err(format!("Profile {} is already bound to device {}", iccid, device).into());
+    }
+
+    bindings.insert(device_id.to_string(), iccid.to_string());
+    save(base_path, &bindings, lock)
+}
+
+/// Forget the device of a profile returned to the pool.
+pub fn unbind(base_path: &Path, iccid: &str, lock: &Lock) -> Result<(), Box<dyn Error>> {
+    let mut bindings = load(base_path)?;
+    let before = bindings.len();
+    bindings.retain(|_, i| i != iccid);
+
+    if bindings.len() != before {
+        log::debug!("Dropped device binding of {}", iccid);
+        save(base_path, &bindings, lock)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pool::{self, tests::pool_with, Claim};
+    use crate::models::store::dir::DirStore;
+    use crate::models::store::{State, Store};
+
+    #[test]
+    fn test_bind_and_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lock::acquire(dir.path()).unwrap();
+        bind(dir.path(), "356938035643809", "89457300000013500452", &lock).unwrap();
+
+        assert_eq!(
+            iccid(dir.path(), "356938035643809").unwrap().as_deref(),
+            Some("89457300000013500452")
+        );
+        assert_eq!(
+            device(dir.path(), "89457300000013500452")
+                .unwrap()
+                .as_deref(),
+            Some("356938035643809")
+        );
+        assert!(iccid(dir.path(), "SN-2").unwrap().is_none());
+
+        // neither side can be bound twice
+        assert!(bind(dir.path(), "356938035643809", "89457300000013500460", &lock).is_err());
+        assert!(bind(dir.path(), "SN-2", "89457300000013500452", &lock).is_err());
+    }
+
+    #[test]
+    fn test_release_drops_binding() {
+        let dir = pool_with(1);
+        let store = DirStore::new(dir.path());
+
+        assert!(matches!(
+            pool::claim_for(&store, "SN-1").unwrap(),
+            Claim::New(_)
+        ));
+        pool::release(&store, "000", "me", "test").unwrap();
+
+        assert!(iccid(dir.path(), "SN-1").unwrap().is_none());
+        assert!(matches!(
+            pool::claim_for(&store, "SN-2").unwrap(),
+            Claim::New(_)
+        ));
+    }
+
+    #[test]
+    fn test_claim_for_bound_device() {
+        let dir = pool_with(2);
+        let store = DirStore::new(dir.path());
+
+        assert!(matches!(
+            pool::claim_for(&store, "SN-1").unwrap(),
+            Claim::New(_)
+        ));
+        assert!(matches!(
+            pool::claim_for(&store, "SN-1").unwrap(),
+            Claim::Bound(iccid) if iccid == "000"
+        ));
+        assert_eq!(store.state("001").unwrap(), Some(State::Available));
+
+        // returning the profile forgets the device as well
+        let Claim::New(claim) = pool::claim_for(&store, "SN-2").unwrap() else {
+            panic!("SN-2 has no profile yet");
+        };
+        pool::rollback(&store, &[claim]).unwrap();
+        assert!(iccid(dir.path(), "SN-2").unwrap().is_none());
+    }
+}
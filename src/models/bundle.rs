@@ -0,0 +1,129 @@
+use super::pool;
+use super::profile::api::Response;
+use super::profile::EncryptedProfile;
+use super::store::Store;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+/// Read the profiles of a file received from elsewhere, either a
+/// `profiles.json` written by `fetch` or a response of the profiles API.
+pub fn read(path: &Path) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+    if let Ok(profiles) = serde_json::from_str::<Vec<EncryptedProfile>>(&content) {
+        return Ok(profiles);
+    }
+    match serde_json::from_str::<Response>(&content) {
+        Ok(response) => Ok(response.profiles),
+        Err(e) => Err(format!(
+            "{} is neither a profiles.json nor an API response: {}",
+            path.display(),
+            e
+        )
+        .into()),
+    }
+}
+
+/// How the profiles of a bundle relate to those already in a store.
+#[derive(Debug, Default)]
+pub struct Merge {
+    /// Profiles not in the store yet.
+    pub new: Vec<EncryptedProfile>,
+    /// ICCIDs whose envelope is already in the store, or earlier in the bundle.
+    pub duplicates: Vec<String>,
+    /// ICCIDs that appear with a different envelope than the one already known.
+    pub conflicts: Vec<String>,
+}
+
+/// Sort `profiles` into new ones, duplicates and conflicts with `store`.
+pub fn merge(store: &dyn Store, profiles: Vec<EncryptedProfile>) -> Result<Merge, Box<dyn Error>> {
+    let mut merge = Merge::default();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for profile in profiles {
+        let iccid = profile.iccid().clone();
+        // the ICCID ends up in a file name
+        pool::check_iccid(&iccid)?;
+
+        let known = match seen.get(&iccid) {
+            Some(&i) => Some(merge.new[i] == profile),
+            None => store.get(&iccid)?.map(|existing| existing == profile),
+        };
+        match known {
+            Some(true) => merge.duplicates.push(iccid),
+            Some(false) => merge.conflicts.push(iccid),
+            None => {
+                seen.insert(iccid, merge.new.len());
+                merge.new.push(profile);
+            }
+        }
+    }
+
+    Ok(merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pool::tests::pool_with;
+    use crate::models::store::dir::DirStore;
+    use std::path::PathBuf;
+
+    fn profile(iccid: &str, ciphertext: &str) -> EncryptedProfile {
+        serde_json::from_str(&format!(
+            r#"{{"iccid":"{}","profile":"{}"}}"#,
+            iccid, ciphertext
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_read_both_formats() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/response.json");
+        let response = read(&path).unwrap();
+        assert_eq!(response.len(), 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profiles.json");
+        std::fs::write(&path, serde_json::to_string(&response).unwrap()).unwrap();
+        assert_eq!(read(&path).unwrap(), response);
+
+        std::fs::write(&path, r#"{"count":1}"#).unwrap();
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        // pool_with stores an empty ciphertext
+        let dir = pool_with(2);
+        let store = DirStore::new(dir.path());
+
+        let merge = merge(
+            &store,
+            vec![
+                profile("000", ""),
+                profile("001", "other"),
+                profile("002", "a"),
+                profile("002", "a"),
+                profile("003", "b"),
+                profile("003", "c"),
+            ],
+        )
+        .unwrap();
+
+        let new: Vec<&String> = merge.new.iter().map(|p| p.iccid()).collect();
+        assert_eq!(new, vec!["002", "003"]);
+        assert_eq!(merge.duplicates, vec!["000", "002"]);
+        assert_eq!(merge.conflicts, vec!["001", "003"]);
+    }
+
+    #[test]
+    fn test_rejects_path_in_iccid() {
+        let dir = pool_with(0);
+        let store = DirStore::new(dir.path());
+        assert!(merge(&store, vec![profile("../x", "")]).is_err());
+        assert!(merge(&store, vec![profile("89abc", "")]).is_err());
+    }
+}
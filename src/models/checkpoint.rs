@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Progress of an interrupted `fetch`, stored next to the fetched profiles.
+///
+/// The extension keeps it out of the way of `store::dir::is_available`.
+pub const CHECKPOINT_FILE: &str = "fetch.checkpoint";
+/// Prefix of the batch files, `profiles_0001.json` and so on.
+pub const BATCH_PREFIX: &str = "profiles_";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    pub url: String,
+    pub requested: u32,
+    pub fetched: u32,
+    /// Batch files written so far, in the order they were fetched.
+    pub batches: Vec<String>,
+}
+
+impl Checkpoint {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(CHECKPOINT_FILE)
+    }
+
+    pub fn load(dir: &Path) -> Result<Option<Checkpoint>, Box<dyn Error>> {
+        let path = Checkpoint::path(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let checkpoint = serde_json::from_str(&content).map_err(|e| {
+            format!(
+                "Failed to parse checkpoint {}. Is the file corrupted? {e}",
+                path.display()
+            )
+        })?;
+        Ok(Some(checkpoint))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let serialized = serde_json::to_string(self)?;
+        super::fs::write_atomic(&Checkpoint::path(dir), serialized.as_bytes())
+    }
+
+    /// Name of the file the next batch is written to.
+    pub fn next_batch(&self) -> String {
+        format!("{}{:04}", BATCH_PREFIX, self.batches.len() + 1)
+    }
+
+    /// Batch files in `dir` the checkpoint doesn't list, in the order they
+    /// were fetched. Left behind by a fetch that stopped after writing a
+    /// batch but before saving the checkpoint.
+    pub fn unrecorded_batches(&self, dir: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut unrecorded = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let is_batch = name
+                .strip_prefix(BATCH_PREFIX)
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+            if is_batch
+                && path.extension().is_some_and(|e| e == "json")
+                && !self.batches.iter().any(|b| b == name)
+            {
+                unrecorded.push(name.to_string());
+            }
+        }
+        unrecorded.sort();
+        Ok(unrecorded)
+    }
+
+    pub fn remove(dir: &Path) -> Result<(), Box<dyn Error>> {
+        match std::fs::remove_file(Checkpoint::path(dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unrecorded_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "profiles_0001.json",
+            "profiles_0002.json",
+            "profiles.json",
+            "profiles_0003.json.tmp",
+            "001.json",
+        ] {
+            std::fs::write(dir.path().join(name), "[]").unwrap();
+        }
+
+        let checkpoint = Checkpoint {
+            batches: vec![String::from("profiles_0001")],
+            ..Default::default()
+        };
+        assert_eq!(
+            checkpoint.unrecorded_batches(dir.path()).unwrap(),
+            vec!["profiles_0002"]
+        );
+        assert_eq!(checkpoint.next_batch(), "profiles_0002");
+    }
+}
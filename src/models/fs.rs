@@ -25,3 +25,39 @@ pub fn store<T: Serialize>(
 
     Ok(())
 }
+
+/// Write `contents` to `path` through a temporary file and a rename, so
+/// readers never see a partially written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+
+    std::fs::write(&tmp, contents)?;
+    if let Err(e) = std::fs::rename(&tmp, path) {
+        log::error!("Failed to write file {}: {}", path.display(), e);
+        let _ = std::fs::remove_file(&tmp);
+        return Err(Box::new(e));
+    }
+
+    Ok(())
+}
+
+/// Move a file, like a rename. Across file systems, where a rename fails,
+/// the file is copied, synced to disk and only then removed from `from`.
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            log::debug!("Copying {} to another file system", from.display());
+            let copied = std::fs::copy(from, to)
+                .and_then(|_| std::fs::File::open(to))
+                .and_then(|file| file.sync_all());
+            if let Err(e) = copied {
+                let _ = std::fs::remove_file(to);
+                return Err(e);
+            }
+            std::fs::remove_file(from)
+        }
+        res => res,
+    }
+}
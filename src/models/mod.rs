@@ -1,2 +1,14 @@
+pub mod audit;
+pub mod binding;
+pub mod bundle;
+pub mod checkpoint;
 pub mod fs;
+pub mod pool;
 pub mod profile;
+pub mod reconcile;
+pub mod rekey;
+pub mod reservation;
+pub mod secret;
+pub mod settings;
+pub mod split;
+pub mod store;
@@ -0,0 +1,550 @@
+use super::profile::EncryptedProfile;
+use super::store::{State, Store};
+use super::{audit, binding, reservation};
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Advisory lock taken while the pool is modified.
+pub const LOCK_FILE: &str = ".softsim.lock";
+
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const LOCK_RETRY: Duration = Duration::from_millis(50);
+
+/// Exclusive lock on a profile directory. Released when dropped.
+///
+/// An OS file lock (`flock`, `LockFileEx` on Windows) on a file in the
+/// directory. It is released by the OS when the process dies, so a lock is
+/// never stale and is held however long the work under it takes. Linux maps
+/// it to a POSIX lock on NFS, which stations sharing a directory see.
+#[derive(Debug)]
+pub struct Lock {
+    file: std::fs::File,
+}
+
+impl Lock {
+    pub fn acquire(dir: &Path) -> Result<Lock, Box<dyn Error>> {
+        let path = dir.join(LOCK_FILE);
+        // never removed, a process waiting on a removed file would hold a
+        // lock nobody else sees
+        let mut file = std::fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| {
+                log::error!("Failed to create lock {}: {}", path.display(), e);
+                e
+            })?;
+        let started = SystemTime::now();
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => {
+                    // owner is informational only, used when debugging a stuck lock
+                    let _ = file
+                        .set_len(0)
+                        .and_then(|_| writeln!(file, "{}", std::process::id()));
+                    log::trace!("Acquired lock {}", path.display());
+                    return Ok(Lock { file });
+                }
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    if started.elapsed().unwrap_or_default() > LOCK_TIMEOUT {
+                        log::error!("Timed out waiting for lock {}", path.display());
+                        return Err(format!(
+                            "Profile directory is locked by another process, see {} for its pid",
+                            path.display()
+                        )
+                        .into());
+                    }
+                    std::thread::sleep(LOCK_RETRY);
+                }
+                Err(std::fs::TryLockError::Error(e)) => {
+                    log::error!("Failed to lock {}: {}", path.display(), e);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.unlock() {
+            log::warn!("Failed to release lock: {}", e);
+        }
+    }
+}
+
+/// Atomically claim `count` unused profiles from `store`.
+///
+/// Either all `count` profiles are claimed or, if the pool runs out, none of
+/// them. Use [`rollback`] to put them back if they could not be used.
+pub fn claim(store: &dyn Store, count: usize) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
+    let lock = Lock::acquire(store.dir())?;
+    take(store, count, &lock)
+}
+
+/// What [`claim_for`] handed out.
+pub enum Claim {
+    /// The device already got a profile, this one.
+    Bound(String),
+    /// A profile new to the device, now bound to it.
+    New(EncryptedProfile),
+}
+
+/// Claim a profile for the device `device_id`, or look up the one it already
+/// got. The lookup and the binding happen under the same lock, so two
+/// stations can't hand out a profile each to the same device.
+pub fn claim_for(store: &dyn Store, device_id: &str) -> Result<Claim, Box<dyn Error>> {
+    let lock = Lock::acquire(store.dir())?;
+    if let Some(iccid) = binding::iccid(store.dir(), device_id)? {
+        return Ok(Claim::Bound(iccid));
+    }
+
+    let claim = take(store, 1, &lock)?.remove(0);
+    if let Err(e) = binding::bind(store.dir(), device_id, claim.iccid(), &lock) {
+        store.put_back(claim.iccid(), &lock)?;
+        return Err(e);
+    }
+    log::debug!("Bound {} to device {}", claim.iccid(), device_id);
+    Ok(Claim::New(claim))
+}
+
+fn take(
+    store: &dyn Store,
+    count: usize,
+    lock: &Lock,
+) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
+    // profiles from reservations nobody committed or aborted are up for grabs again
+    for expired in reservation::expire(store, lock)? {
+        log::warn!(
+            "Reservation {} expired. Returned {} to the pool",
+            expired.token,
+            expired.iccids.join(", ")
+        );
+        audit::append(
+            store.dir(),
+            audit::Event::Expire {
+                token: expired.token,
+                iccids: expired.iccids,
+            },
+            lock,
+        )?;
+    }
+
+    let claims = store.take(count, lock)?;
+    if claims.is_empty() {
+        log::error!("No profiles found at {}", store.dir().display());
+        return Err("No profiles was found".into());
+    }
+    if claims.len() < count {
+        log::error!(
+            "Requested {} profiles but only {} are available at {}",
+            count,
+            claims.len(),
+            store.dir().display()
+        );
+        for claim in &claims {
+            store.put_back(claim.iccid(), lock)?;
+        }
+        return Err(LowInventory {
+            available: claims.len(),
+            minimum: count,
+        }
+        .into());
+    }
+
+    Ok(claims)
+}
+
+/// Return all `claims` to the pool, along with a device binding made by
+/// [`claim_for`].
+pub fn rollback(store: &dyn Store, claims: &[EncryptedProfile]) -> Result<(), Box<dyn Error>> {
+    let lock = Lock::acquire(store.dir())?;
+    let mut err = None;
+    for claim in claims {
+        // keep going, every profile that can be returned should be
+        let returned = store
+            .put_back(claim.iccid(), &lock)
+            .and_then(|_| binding::unbind(store.dir(), claim.iccid(), &lock));
+        if let Err(e) = returned {
+            log::error!("Failed to return profile to the pool: {}", e);
+            err = Some(e);
+        }
+    }
+    err.map_or(Ok(()), Err)
+}
+
+/// Move a claimed profile that can't be used out of the pool, e.g. one that
+/// doesn't decrypt or holds invalid fields. Put back, it would be the first
+/// one claimed again and fail every following claim the same way.
+pub fn quarantine(store: &dyn Store, iccid: &str, reason: &str) -> Result<(), Box<dyn Error>> {
+    let lock = Lock::acquire(store.dir())?;
+    store.quarantine(iccid, &lock)?;
+    binding::unbind(store.dir(), iccid, &lock)?;
+    log::error!("Moved profile {} out of the pool: {}", iccid, reason);
+    audit::append(
+        store.dir(),
+        audit::Event::Quarantine {
+            iccid: iccid.to_string(),
+            reason: reason.to_string(),
+        },
+        &lock,
+    )
+}
+
+/// Record that a consumed profile was written to a device. A provisioned
+/// profile can no longer be released.
+pub fn confirm(store: &dyn Store, iccid: &str, by: &str) -> Result<(), Box<dyn Error>> {
+    check_iccid(iccid)?;
+    let lock = Lock::acquire(store.dir())?;
+
+    if let Some(token) = reservation::token_of(store.dir(), iccid)? {
+        return Err(format!(
+            "Profile {} is reserved. Use `commit {}` to confirm it",
+            iccid, token
+        )
+        .into());
+    }
+
+    mark_provisioned(store, iccid, &lock)?;
+    audit::append(
+        store.dir(),
+        audit::Event::Confirm {
+            iccid: iccid.to_string(),
+            by: by.to_string(),
+        },
+        &lock,
+    )
+}
+
+/// Like [`confirm`], for callers already holding the lock.
+pub fn mark_provisioned(store: &dyn Store, iccid: &str, lock: &Lock) -> Result<(), Box<dyn Error>> {
+    match store.state(iccid)? {
+        Some(State::Consumed) => store.provision(iccid, lock),
+        Some(State::Provisioned) => Err(format!("Profile {} is already confirmed", iccid).into()),
+        _ => Err(format!("Profile {} has not been handed out", iccid).into()),
+    }
+}
+
+/// Return a consumed profile to the pool, e.g. after flashing a board failed.
+pub fn release(
+    store: &dyn Store,
+    iccid: &str,
+    by: &str,
+    reason: &str,
+) -> Result<(), Box<dyn Error>> {
+    check_iccid(iccid)?;
+    let lock = Lock::acquire(store.dir())?;
+
+    if let Some(token) = reservation::token_of(store.dir(), iccid)? {
+        return Err(format!(
+            "Profile {} is reserved. Use `abort {}` to return it",
+            iccid, token
+        )
+        .into());
+    }
+
+    unclaim(store, iccid, &lock)?;
+    audit::append(
+        store.dir(),
+        audit::Event::Release {
+            iccid: iccid.to_string(),
+            by: by.to_string(),
+            reason: reason.to_string(),
+        },
+        &lock,
+    )
+}
+
+/// Reject an ICCID given by an operator that isn't all digits. It ends up
+/// in file names.
+pub fn check_iccid(iccid: &str) -> Result<(), Box<dyn Error>> {
+    if iccid.is_empty() || !iccid.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid ICCID '{}'", iccid).into());
+    }
+    Ok(())
+}
+
+/// Like [`release`], for callers already holding the lock.
+pub fn unclaim(store: &dyn Store, iccid: &str, lock: &Lock) -> Result<(), Box<dyn Error>> {
+    match store.state(iccid)? {
+        Some(State::Consumed) => {}
+        Some(State::Provisioned) => {
+            return Err(format!(
+                "Profile {} is confirmed as provisioned and can't be released",
+                iccid
+            )
+            .into())
+        }
+        Some(State::Available) => return Err(format!("Profile {} is not in use", iccid).into()),
+        None => {
+            return Err(format!("Profile {} not found in {}", iccid, store.dir().display()).into())
+        }
+    }
+
+    store.put_back(iccid, lock)?;
+    // the next device to get this profile is a different one
+    binding::unbind(store.dir(), iccid, lock)
+}
+
+/// Group sorted ICCIDs into runs of consecutive numbers.
+///
+/// The last digit of an ICCID is a Luhn check digit, so two ICCIDs are
+/// consecutive if they are one apart without it.
+pub fn ranges(iccids: &[String]) -> Vec<(String, String, usize)> {
+    fn consecutive(a: &str, b: &str) -> bool {
+        let number = |s: &str| s.parse::<u128>().ok();
+        let body = |s: &str| number(s.get(..s.len().saturating_sub(1))?);
+
+        let full = matches!((number(a), number(b)), (Some(a), Some(b)) if a + 1 == b);
+        let without_check = matches!((body(a), body(b)), (Some(a), Some(b)) if a + 1 == b);
+        a.len() == b.len() && (full || without_check)
+    }
+
+    let mut ranges: Vec<(String, String, usize)> = Vec::new();
+    for iccid in iccids {
+        match ranges.last_mut() {
+            Some((_, last, n)) if consecutive(last, iccid) => {
+                *last = iccid.clone();
+                *n += 1;
+            }
+            _ => ranges.push((iccid.clone(), iccid.clone(), 1)),
+        }
+    }
+    ranges
+}
+
+/// Returned when fewer profiles than requested are left in the pool.
+#[derive(Debug)]
+pub struct LowInventory {
+    pub available: usize,
+    pub minimum: usize,
+}
+
+impl fmt::Display for LowInventory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Only {} profiles available, expected at least {}",
+            self.available, self.minimum
+        )
+    }
+}
+
+impl Error for LowInventory {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::models::store::dir::DirStore;
+    use std::collections::HashSet;
+
+    pub fn pool_with(n: usize) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..n {
+            let iccid = format!("{:03}", i);
+            std::fs::write(
+                dir.path().join(format!("{}.json", iccid)),
+                format!(r#"{{"iccid":"{}","profile":""}}"#, iccid),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.path().join("profiles.json"), "[]").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = || {
+            std::fs::File::options()
+                .write(true)
+                .open(dir.path().join(LOCK_FILE))
+                .unwrap()
+        };
+
+        let lock = Lock::acquire(dir.path()).unwrap();
+        assert!(matches!(
+            other().try_lock(),
+            Err(std::fs::TryLockError::WouldBlock)
+        ));
+        drop(lock);
+        other().try_lock().unwrap();
+    }
+
+    #[test]
+    fn test_claim_marks_and_rollback_restores() {
+        let dir = pool_with(1);
+        let store = DirStore::new(dir.path());
+
+        let claims = claim(&store, 1).unwrap();
+        assert_eq!(claims[0].iccid(), "000");
+        assert!(dir.path().join("__000.json").exists());
+        assert!(claim(&store, 1).is_err());
+
+        rollback(&store, &claims).unwrap();
+        assert!(dir.path().join("000.json").exists());
+        // released, a second lock doesn't have to wait
+        drop(Lock::acquire(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_claim_skips_corrupt_profile() {
+        let dir = pool_with(3);
+        let store = DirStore::new(dir.path());
+        std::fs::write(dir.path().join("000.json"), "{").unwrap();
+
+        let claims = claim(&store, 2).unwrap();
+        assert_eq!(claims[0].iccid(), "001");
+        assert_eq!(claims[1].iccid(), "002");
+        assert!(dir.path().join("000.invalid").exists());
+        assert!(matches!(
+            &audit::read(dir.path()).unwrap()[0].event,
+            audit::Event::Quarantine { iccid, .. } if iccid == "000"
+        ));
+    }
+
+    #[test]
+    fn test_claim_is_all_or_nothing() {
+        let dir = pool_with(3);
+        let store = DirStore::new(dir.path());
+
+        let err = claim(&store, 4).unwrap_err();
+        assert!(err.is::<LowInventory>());
+        assert_eq!(store.inventory().unwrap().available.len(), 3);
+
+        let claims = claim(&store, 3).unwrap();
+        assert_eq!(claims.len(), 3);
+        assert!(store.inventory().unwrap().available.is_empty());
+
+        rollback(&store, &claims).unwrap();
+        assert_eq!(store.inventory().unwrap().available.len(), 3);
+    }
+
+    #[test]
+    fn test_concurrent_claims_are_exclusive() {
+        let dir = pool_with(40);
+        let path = dir.path().to_path_buf();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let store = DirStore::new(&path);
+                    let mut claimed = Vec::new();
+                    while let Ok(c) = claim(&store, 1) {
+                        claimed.push(c[0].iccid().clone());
+                    }
+                    claimed
+                })
+            })
+            .collect();
+
+        let all: Vec<String> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        let unique: HashSet<_> = all.iter().collect();
+
+        assert_eq!(all.len(), 40);
+        assert_eq!(unique.len(), 40);
+    }
+
+    #[test]
+    fn test_inventory() {
+        let dir = pool_with(3);
+        let store = DirStore::new(dir.path());
+        std::fs::write(dir.path().join("fetch.checkpoint"), "{}").unwrap();
+        claim(&store, 1).unwrap();
+
+        let inventory = store.inventory().unwrap();
+        assert_eq!(inventory.available, vec!["001", "002"]);
+        assert_eq!(inventory.consumed, vec!["000"]);
+        assert_eq!(inventory.total(), 3);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let iccids: Vec<String> = [
+            "89457300000013500452",
+            "89457300000013500460",
+            "89457300000013500478",
+            "89457300000013500510",
+            "001",
+            "002",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let ranges = ranges(&iccids);
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(
+            ranges[0],
+            (
+                "89457300000013500452".to_string(),
+                "89457300000013500478".to_string(),
+                3
+            )
+        );
+        assert_eq!(ranges[2], ("001".to_string(), "002".to_string(), 2));
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = pool_with(2);
+        let store = DirStore::new(dir.path());
+
+        let claims = claim(&store, 1).unwrap();
+        quarantine(&store, claims[0].iccid(), "doesn't decrypt").unwrap();
+        assert!(dir.path().join("000.invalid").exists());
+        assert_eq!(store.state("000").unwrap(), None);
+        assert!(audit::read(dir.path())
+            .unwrap()
+            .iter()
+            .any(|r| matches!(&r.event, audit::Event::Quarantine { iccid, .. } if iccid == "000")));
+
+        // the next claim gets past it
+        assert_eq!(claim(&store, 1).unwrap()[0].iccid(), "001");
+    }
+
+    #[test]
+    fn test_release_and_confirm() {
+        let dir = pool_with(2);
+        let store = DirStore::new(dir.path());
+        assert!(release(&store, "000", "me", "test").is_err());
+        assert!(confirm(&store, "000", "me").is_err());
+
+        claim(&store, 2).unwrap();
+        release(&store, "000", "me", "test").unwrap();
+        assert!(dir.path().join("000.json").exists());
+
+        confirm(&store, "001", "me").unwrap();
+        assert!(confirm(&store, "001", "me").is_err());
+        assert!(release(&store, "001", "me", "test").is_err());
+        assert!(dir.path().join("__001.json").exists());
+
+        let events: Vec<_> = audit::read(dir.path()).unwrap();
+        assert!(matches!(&events[0].event, audit::Event::Release { iccid, .. } if iccid == "000"));
+        assert!(matches!(&events[1].event, audit::Event::Confirm { iccid, .. } if iccid == "001"));
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn test_rejects_path_as_iccid() {
+        let dir = pool_with(1);
+        let store = DirStore::new(dir.path());
+        claim(&store, 1).unwrap();
+        std::fs::write(dir.path().join("__x.json"), "{}").unwrap();
+
+        for iccid in ["../x", "x", "", "000/"] {
+            assert!(release(&store, iccid, "me", "test").is_err());
+            assert!(confirm(&store, iccid, "me").is_err());
+        }
+        assert!(dir.path().join("__x.json").exists());
+    }
+}
@@ -1,25 +1,101 @@
+use super::secret::mask;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+use zeroize::Zeroizing;
 pub mod api;
 pub mod crypto;
+pub mod decoder;
 pub mod encoder;
+pub mod validation;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Profile {
     pub iccid: Option<String>,
-    pub k: Option<String>,
-    pub opc: Option<String>,
-    pub kid: Option<String>,
-    pub kic: Option<String>,
+    pub k: Option<SecretString>,
+    pub opc: Option<SecretString>,
+    pub kid: Option<SecretString>,
+    pub kic: Option<SecretString>,
     pub imsi: Option<String>,
-    pub pin: Option<String>,
-    pub puk: Option<String>,
-    pub adm: Option<String>,
+    pub pin: Option<SecretString>,
+    pub puk: Option<SecretString>,
+    pub adm: Option<SecretString>,
     pub smsp: Option<String>,
     pub smsc: Option<String>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+impl Profile {
+    /// Fields a profile can't attach to the network or be managed OTA without.
+    pub fn missing_fields(&self) -> Vec<&'static str> {
+        [
+            ("imsi", self.imsi.is_none()),
+            ("k", self.k.is_none()),
+            ("opc", self.opc.is_none()),
+            ("kic", self.kic.is_none()),
+            ("kid", self.kid.is_none()),
+        ]
+        .into_iter()
+        .filter(|(_, missing)| *missing)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Copy for people to read, with K, OPc, KIc, KID and ADM masked.
+    pub fn masked(&self) -> Profile {
+        let masked = |s: &Option<SecretString>| s.as_deref().map(|s| SecretString::from(mask(s)));
+        Profile {
+            k: masked(&self.k),
+            opc: masked(&self.opc),
+            kid: masked(&self.kid),
+            kic: masked(&self.kic),
+            adm: masked(&self.adm),
+            ..self.clone()
+        }
+    }
+}
+
+/// A secret field of a profile, such as K or the PIN. Wiped from memory when
+/// dropped, and masked in `Debug` output so it can't end up in a log.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct SecretString(Zeroizing<String>);
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> SecretString {
+        SecretString(Zeroizing::new(s))
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> SecretString {
+        SecretString::from(s.to_string())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", mask(self))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct EncryptedProfile {
     iccid: String,
     profile: String,
+    /// How `profile` is encrypted, e.g. `RSA-OAEP` or `RSA-OAEP-256+A256GCM`,
+    /// see [`crypto::Padding`]. Detected when decrypting if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alg: Option<String>,
+    /// Fingerprint of the public key `profile` is encrypted to, see
+    /// [`crypto::PublicKey::fingerprint`]. Not given by the API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
 }
 
 impl EncryptedProfile {
@@ -30,4 +106,47 @@ impl EncryptedProfile {
     pub fn profile(&self) -> &String {
         &self.profile
     }
+
+    pub fn alg(&self) -> Option<&str> {
+        self.alg.as_deref()
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_left_out_of_debug() {
+        let json = r#"{"iccid":"89457300000013500452","k":"000102030405060708090A0B0C0D0E0F","opc":null,"kid":null,"kic":null,"imsi":"234602102350049","pin":"1234","puk":null,"adm":null,"smsp":null,"smsc":null}"#;
+        let profile: Profile = serde_json::from_str(json).unwrap();
+
+        let debug = format!("{:?}", profile);
+        assert!(debug.contains("89457300000013500452"));
+        assert!(!debug.contains("000102030405060708090A0B0C0D0E0F"));
+        assert!(!debug.contains("1234"));
+
+        // serialized as is
+        assert_eq!(serde_json::to_string(&profile).unwrap(), json);
+        assert_eq!(profile.pin.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn test_masked() {
+        let json = r#"{"iccid":"89457300000013500452","k":"000102030405060708090A0B0C0D0E0F","opc":null,"kid":null,"kic":null,"imsi":"234602102350049","pin":"1234","puk":null,"adm":"12345678","smsp":null,"smsc":null}"#;
+        let profile: Profile = serde_json::from_str(json).unwrap();
+
+        let masked = profile.masked();
+        assert_eq!(
+            masked.k.as_deref(),
+            Some("00****************************0F")
+        );
+        assert_eq!(masked.adm.as_deref(), Some("********"));
+        assert_eq!(masked.iccid, profile.iccid);
+        assert_eq!(masked.pin.as_deref(), Some("1234"));
+    }
 }
@@ -1,59 +1,258 @@
-use super::EncryptedProfile;
-use reqwest::{header::HeaderMap, header::AUTHORIZATION, header::CONTENT_TYPE, Client};
+use super::{EncryptedProfile, SecretString};
+use reqwest::{
+    header::HeaderMap, header::HeaderValue, header::AUTHORIZATION, header::CONTENT_TYPE,
+    header::RETRY_AFTER, Client, StatusCode,
+};
+use rsa::rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
     pub profiles: Vec<EncryptedProfile>,
     pub count: u32,
 }
 
+/// A SIM as listed by the SIMs API. Only the fields needed here.
+#[derive(Deserialize, Debug)]
+pub struct Sim {
+    pub iccid: String,
+    #[serde(default)]
+    pub activated: Option<bool>,
+}
+
+impl Sim {
+    /// SIMs without an `activated` field count as active.
+    pub fn is_active(&self) -> bool {
+        self.activated != Some(false)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SimsResponse {
+    sims: Vec<Sim>,
+    #[serde(default)]
+    pagination: Option<Pagination>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Pagination {
+    next_page: Option<String>,
+}
+
 pub struct Config {
-    pub api_key: String,
+    pub api_key: SecretString,
     pub url: String,
+    /// Number of times a failing call is retried before giving up.
+    pub retries: u32,
+    /// Delay before the first retry. Doubled for every following attempt.
+    pub retry_delay: Duration,
 }
 
+pub const DEFAULT_URL: &str = "https://api.onomondo.com/sims/profiles";
+
 const MAX_COUNT: u32 = 1000;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
-pub async fn get(config: &Config, count: u32) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
-    let api = Client::new();
+#[derive(Debug)]
+pub enum ApiError {
+    /// Temporary failure, e.g. 429, 5xx or a dropped connection.
+    Retryable {
+        reason: String,
+        retry_after: Option<Duration>,
+        /// The server may have acted on the call, e.g. it timed out after
+        /// the request was sent.
+        maybe_handled: bool,
+    },
+    /// Repeating the call won't help, e.g. a wrong API key.
+    Fatal(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Retryable { reason, .. } => write!(f, "{}", reason),
+            ApiError::Fatal(reason) => write!(f, "{}", reason),
+        }
+    }
+}
 
-    let calls = (count as f32 / MAX_COUNT as f32).ceil() as u32;
-    let count_last_call = count - (calls - 1) * MAX_COUNT;
+impl Error for ApiError {}
 
-    let mut profiles: Vec<EncryptedProfile> = Vec::new();
-    let mut err = None;
-    for i in 0..calls {
-        let count: u32 = if i == (calls - 1) {
-            count_last_call
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() {
+            ApiError::Retryable {
+                reason: e.to_string(),
+                retry_after: None,
+                // only a failed connection certainly never reached the server
+                maybe_handled: !e.is_connect(),
+            }
         } else {
-            MAX_COUNT
-        };
+            ApiError::Fatal(e.to_string())
+        }
+    }
+}
 
-        log::debug!("Fetching {} profiles", count);
+/// Fetch `count` profiles in batches of up to `MAX_COUNT`.
+///
+/// Every batch is handed to `on_batch` as soon as it arrives, so profiles from
+/// successful calls are kept even if a later call fails. Returns the number
+/// of profiles fetched.
+///
+/// The server hands out the profiles of a call whether or not the response
+/// arrives, so a call is only retried if it certainly didn't reach the server.
+pub async fn get<F>(config: &Config, count: u32, mut on_batch: F) -> Result<u32, Box<dyn Error>>
+where
+    F: FnMut(Vec<EncryptedProfile>) -> Result<(), Box<dyn Error>>,
+{
+    let api = Client::new();
+
+    let mut fetched: u32 = 0;
+    while fetched < count {
+        let count = (count - fetched).min(MAX_COUNT);
 
-        let resp = get_profiles_helper(&api, count, config).await;
+        log::debug!("Fetching {} profiles", count);
 
-        let resp = match resp {
+        let resp = match with_retry(config, false, || get_profiles_helper(&api, count, config))
+            .await
+        {
             Ok(r) => r,
             Err(e) => {
                 log::error!("Failed to retrive profiles - {}", e);
-                err = Some(e);
-                break;
+                if fetched == 0 {
+                    return Err(e.into());
+                }
+                return Err(format!("Fetched {} profiles before failing: {}", fetched, e).into());
             }
         };
 
-        profiles.extend(resp);
+        if resp.is_empty() {
+            break;
+        }
+
+        fetched += resp.len() as u32;
+        on_batch(resp)?;
     }
 
-    if !profiles.is_empty() {
-        log::info!("Got {} profiles", profiles.len());
-        Ok(profiles)
+    if fetched > 0 {
+        log::info!("Got {} profiles", fetched);
+        Ok(fetched)
     } else {
-        Err(err.unwrap_or("No profiles found".into()))
+        Err("No profiles found".into())
+    }
+}
+
+/// ICCIDs of all activated SIMs of the account, following the pages of the
+/// SIMs API.
+pub async fn active_iccids(config: &Config) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let api = Client::new();
+
+    let mut iccids = BTreeSet::new();
+    let mut page: Option<String> = None;
+    loop {
+        let resp = with_retry(config, true, || {
+            get_sims_helper(&api, page.as_deref(), config)
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to list SIMs - {}", e);
+            e
+        })?;
+
+        iccids.extend(
+            resp.sims
+                .into_iter()
+                .filter(Sim::is_active)
+                .map(|s| s.iccid),
+        );
+        match resp.pagination.and_then(|p| p.next_page) {
+            Some(next) if page.as_ref() != Some(&next) => page = Some(next),
+            _ => break,
+        }
+    }
+
+    log::info!("Found {} active SIMs", iccids.len());
+    Ok(iccids)
+}
+
+/// Call until it succeeds, fails for good or runs out of retries. Calls that
+/// aren't `idempotent` are only repeated if the server never acted on them.
+async fn with_retry<T, F, Fut>(
+    config: &Config,
+    idempotent: bool,
+    mut call: F,
+) -> Result<T, ApiError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ApiError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Err(ApiError::Retryable {
+                reason,
+                maybe_handled: true,
+                ..
+            }) if !idempotent => {
+                return Err(ApiError::Fatal(format!(
+                    "{} - not retried, the server may have handed out profiles already",
+                    reason
+                )))
+            }
+            Err(ApiError::Retryable {
+                reason,
+                retry_after,
+                ..
+            }) if attempt < config.retries => {
+                let delay = retry_after.unwrap_or_else(|| backoff(config.retry_delay, attempt));
+                attempt += 1;
+                log::warn!(
+                    "{} - retrying in {:.1}s ({}/{})",
+                    reason,
+                    delay.as_secs_f32(),
+                    attempt,
+                    config.retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            res => return res,
+        }
     }
 }
 
+/// Exponential backoff with jitter. The delay doubles for every attempt and
+/// is capped at `MAX_RETRY_DELAY`. A random half of it is added on top of
+/// the other half, so stations retrying together spread out.
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let delay = base
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY);
+    let half = delay / 2;
+    let jitter = OsRng.next_u64() % (half.as_millis() as u64 + 1);
+
+    half + Duration::from_millis(jitter)
+}
+
+/// Parse a `Retry-After` header, given either as seconds or as an HTTP date.
+/// Capped at `MAX_RETRY_DELAY`, a server asking for more doesn't stall a run.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    let wait = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            let wait = at.signed_duration_since(chrono::Utc::now());
+            wait.to_std().unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(wait.min(MAX_RETRY_DELAY))
+}
+
 #[derive(Serialize)]
 struct RequestBody {
     count: u32,
@@ -63,11 +262,13 @@ async fn get_profiles_helper(
     client: &Client,
     count: u32,
     config: &Config,
-) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
+) -> Result<Vec<EncryptedProfile>, ApiError> {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
     let body = RequestBody { count };
     let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, config.api_key.parse().unwrap());
+    let api_key = HeaderValue::from_str(&config.api_key)
+        .map_err(|_| ApiError::Fatal("The API key isn't a valid header value".into()))?;
+    headers.insert(AUTHORIZATION, api_key);
     headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
 
     let response = client
@@ -80,16 +281,140 @@ async fn get_profiles_helper(
 
     log::trace!("{:?}", response);
     match response.status() {
-        reqwest::StatusCode::OK => {
-            let a = response.json::<Response>().await?;
+        StatusCode::OK => {
+            let a = response
+                .json::<Response>()
+                .await
+                .map_err(|e| ApiError::Fatal(format!("Failed to parse response: {}", e)))?;
             Ok(a.profiles)
         }
-        reqwest::StatusCode::UNAUTHORIZED => Err("Unauthorized".into()),
-        reqwest::StatusCode::NOT_FOUND => Err("No more profiles are available".into()),
-        _ => {
-            let msg = response.text().await?;
-            log::error!("{msg}");
-            Err(msg.into())
+        StatusCode::NOT_FOUND => Err(ApiError::Fatal("No more profiles are available".into())),
+        _ => Err(status_error(response).await),
+    }
+}
+
+async fn get_sims_helper(
+    client: &Client,
+    page: Option<&str>,
+    config: &Config,
+) -> Result<SimsResponse, ApiError> {
+    let mut request = client
+        .get(config.url.clone())
+        .header(AUTHORIZATION, &*config.api_key);
+    if let Some(page) = page {
+        request = request.query(&[("next_page", page)]);
+    }
+    let response = request.send().await?;
+
+    log::trace!("{:?}", response);
+    match response.status() {
+        StatusCode::OK => response
+            .json::<SimsResponse>()
+            .await
+            .map_err(|e| ApiError::Fatal(format!("Failed to parse response: {}", e))),
+        _ => Err(status_error(response).await),
+    }
+}
+
+/// The error for a response that wasn't a success.
+async fn status_error(response: reqwest::Response) -> ApiError {
+    match response.status() {
+        StatusCode::UNAUTHORIZED => ApiError::Fatal("Unauthorized".into()),
+        status @ (StatusCode::REQUEST_TIMEOUT
+        | StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT) => {
+            let retry_after = retry_after(response.headers());
+            let msg = response.text().await.unwrap_or_default();
+            log::debug!("{status}: {msg}");
+            ApiError::Retryable {
+                reason: format!("{status} {msg}").trim_end().to_string(),
+                retry_after,
+                // turned away before the call was handled
+                maybe_handled: !matches!(
+                    status,
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+                ),
+            }
+        }
+        _ => match response.text().await {
+            Ok(msg) => {
+                log::error!("{msg}");
+                ApiError::Fatal(msg)
+            }
+            Err(e) => e.into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let base = Duration::from_millis(1000);
+        for _ in 0..100 {
+            let first = backoff(base, 0);
+            assert!(first >= Duration::from_millis(500) && first <= base);
+
+            let third = backoff(base, 2);
+            assert!(third >= Duration::from_millis(2000) && third <= Duration::from_millis(4000));
+
+            assert!(backoff(base, 40) <= MAX_RETRY_DELAY);
         }
     }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+
+        headers.insert(RETRY_AFTER, "86400".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(MAX_RETRY_DELAY));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    async fn attempts(idempotent: bool, maybe_handled: bool) -> u32 {
+        let config = Config {
+            api_key: SecretString::from(""),
+            url: String::new(),
+            retries: 3,
+            retry_delay: Duration::ZERO,
+        };
+        let calls = std::cell::Cell::new(0);
+        let res: Result<(), ApiError> = with_retry(&config, idempotent, || {
+            calls.set(calls.get() + 1);
+            async move {
+                Err(ApiError::Retryable {
+                    reason: String::from("502 Bad Gateway"),
+                    retry_after: None,
+                    maybe_handled,
+                })
+            }
+        })
+        .await;
+        assert!(res.is_err());
+        calls.get()
+    }
+
+    #[tokio::test]
+    async fn test_calls_that_may_have_been_handled_are_not_repeated() {
+        assert_eq!(attempts(false, true).await, 1);
+        assert_eq!(attempts(false, false).await, 4);
+        assert_eq!(attempts(true, true).await, 4);
+    }
 }
@@ -0,0 +1,219 @@
+use super::encoder::{swap_nibbles, Tags};
+use super::Profile;
+use std::error::Error;
+use zeroize::Zeroizing;
+
+impl Profile {
+    /// Parse the hex TLV stream produced by [`Profile::to_hex`] back into a profile.
+    pub fn from_hex(hex: &str) -> Result<Profile, Box<dyn Error>> {
+        from_hex(hex)
+    }
+}
+
+impl TryFrom<u8> for Tags {
+    type Error = String;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        match tag {
+            1 => Ok(Tags::Imsi),
+            2 => Ok(Tags::Iccid),
+            3 => Ok(Tags::Opc),
+            4 => Ok(Tags::Ki),
+            5 => Ok(Tags::Kic),
+            6 => Ok(Tags::Kid),
+            7 => Ok(Tags::Smsp),
+            8 => Ok(Tags::Pin),
+            10 => Ok(Tags::Adm),
+            11 => Ok(Tags::Puk),
+            12 => Ok(Tags::Smsc),
+            0xff => Ok(Tags::End),
+            _ => Err(format!("Unknown tag {:02x}", tag)),
+        }
+    }
+}
+
+fn from_hex(hex: &str) -> Result<Profile, Box<dyn Error>> {
+    let hex: Zeroizing<String> =
+        Zeroizing::new(hex.chars().filter(|c| !c.is_whitespace()).collect());
+    if hex.is_empty() {
+        return Err("Profile is empty".into());
+    }
+    if !hex.is_ascii() {
+        return Err("Profile contains non-hex characters".into());
+    }
+
+    let mut p = Profile {
+        iccid: None,
+        k: None,
+        opc: None,
+        kid: None,
+        kic: None,
+        imsi: None,
+        pin: None,
+        puk: None,
+        adm: None,
+        smsp: None,
+        smsc: None,
+    };
+
+    let mut offset = 0;
+    while offset < hex.len() {
+        let header = hex
+            .get(offset..offset + 4)
+            .ok_or_else(|| format!("Truncated TLV header at offset {}", offset))?;
+        let tag = u8::from_str_radix(&header[..2], 16)
+            .map_err(|_| format!("Invalid tag '{}' at offset {}", &header[..2], offset))?;
+        let tag = Tags::try_from(tag).map_err(|e| format!("{} at offset {}", e, offset))?;
+        if let Tags::End = tag {
+            break;
+        }
+
+        // length counts hex characters, not octets. See Tlv::encode_tlv.
+        let len = usize::from_str_radix(&header[2..], 16)
+            .map_err(|_| format!("Invalid length '{}' at offset {}", &header[2..], offset))?;
+        let value = hex
+            .get(offset + 4..offset + 4 + len)
+            .ok_or_else(|| format!("Truncated value for tag at offset {}", offset))?;
+        offset += 4 + len;
+
+        let duplicate = match tag {
+            Tags::Imsi => p.imsi.replace(decode_imsi(value)?).is_some(),
+            Tags::Iccid => p
+                .iccid
+                .replace(strip_padding(&swap_nibbles(value)))
+                .is_some(),
+            Tags::Opc => p.opc.replace(value.into()).is_some(),
+            Tags::Ki => p.k.replace(value.into()).is_some(),
+            Tags::Kic => p.kic.replace(value.into()).is_some(),
+            Tags::Kid => p.kid.replace(value.into()).is_some(),
+            Tags::Smsp => p.smsp.replace(value.to_string()).is_some(),
+            Tags::Pin => p.pin.replace(decode_ascii(value)?.into()).is_some(),
+            Tags::Adm => p.adm.replace(decode_ascii(value)?.into()).is_some(),
+            Tags::Puk => p.puk.replace(decode_ascii(value)?.into()).is_some(),
+            Tags::Smsc => p.smsc.replace(decode_smsc(value)?).is_some(),
+            Tags::End => unreachable!(),
+        };
+        if duplicate {
+            return Err(format!("Duplicate tag at offset {}", offset - 4 - len).into());
+        }
+    }
+
+    Ok(p)
+}
+
+fn strip_padding(s: &str) -> String {
+    s.trim_end_matches(['f', 'F']).to_string()
+}
+
+fn decode_imsi(value: &str) -> Result<String, Box<dyn Error>> {
+    let l = value
+        .get(..2)
+        .and_then(|l| usize::from_str_radix(l, 16).ok())
+        .ok_or("Invalid IMSI length")?;
+    // EF.IMSI is always 9 octets, octets past the length are padded with ff
+    let (digits, padding) = value[2..].split_at_checked(2 * l).unwrap_or(("", ""));
+    if digits.is_empty() || !padding.chars().all(|c| c.eq_ignore_ascii_case(&'f')) {
+        return Err(format!(
+            "IMSI length {} does not match content of {} octets",
+            l,
+            (value.len() - 2) / 2
+        )
+        .into());
+    }
+
+    // first nibble holds the odd/even indicator and the identity type
+    let swapped = swap_nibbles(digits);
+    Ok(strip_padding(swapped.get(1..).unwrap_or("")))
+}
+
+fn decode_smsc(value: &str) -> Result<String, Box<dyn Error>> {
+    let length_octet = value
+        .get(..2)
+        .and_then(|l| usize::from_str_radix(l, 16).ok())
+        .ok_or("Invalid SMSC length")?;
+    let ton = value.get(2..4).ok_or("Truncated SMSC")?;
+    let digits_octets = length_octet.checked_sub(1).ok_or("Invalid SMSC length")?;
+    let digits = value
+        .get(4..4 + 2 * digits_octets)
+        .ok_or("SMSC length exceeds content")?;
+
+    let number = strip_padding(&swap_nibbles(digits));
+    if ton.eq_ignore_ascii_case("91") {
+        Ok(format!("+{}", number))
+    } else {
+        Ok(number)
+    }
+}
+
+fn decode_ascii(value: &str) -> Result<String, Box<dyn Error>> {
+    // the value is a PIN, PUK or ADM, keep it out of the error
+    let bytes = hex::decode(value).map_err(|e| format!("Invalid hex: {}", e))?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_profile() -> Profile {
+        Profile {
+            iccid: Some(String::from("89000123456789012341")),
+            imsi: Some(String::from("001010123456063")),
+            opc: Some("00000000000000000000000000000000".into()),
+            k: Some("000102030405060708090A0B0C0D0E0F".into()),
+            kic: Some("000102030405060708090A0B0C0D0E0F".into()),
+            kid: Some("000102030405060708090A0B0C0D0E0F".into()),
+            pin: Some("1234".into()),
+            puk: Some("12345678".into()),
+            adm: Some("87654321".into()),
+            smsp: Some(String::from("abcd")),
+            smsc: Some(String::from("+44779770484")),
+        }
+    }
+
+    #[test]
+    fn test_decode_readme_profile() {
+        let p = Profile::from_hex("01120809101010325406360214980010325476981032140320000000000000000000000000000000000420000102030405060708090A0B0C0D0E0F0520000102030405060708090A0B0C0D0E0F0620000102030405060708090A0B0C0D0E0F").unwrap();
+
+        assert_eq!(p.imsi.as_deref(), Some("001010123456063"));
+        assert_eq!(p.iccid.as_deref(), Some("89000123456789012341"));
+        assert_eq!(p.k.as_deref(), Some("000102030405060708090A0B0C0D0E0F"));
+        assert!(p.pin.is_none());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let p = full_profile();
+        let decoded = Profile::from_hex(&p.to_hex(true, true)).unwrap();
+
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            serde_json::to_string(&p).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_even_imsi() {
+        assert_eq!(
+            decode_imsi("080910101032540636").unwrap(),
+            "001010123456063"
+        );
+        assert_eq!(decode_imsi("0801101010325406f6").unwrap(), "00101012345606");
+    }
+
+    #[test]
+    fn test_padded_imsi() {
+        assert_eq!(decode_imsi("0709101010325466ff").unwrap(), "0010101234566");
+        assert!(decode_imsi("070910101032546612").is_err());
+        assert!(decode_imsi("0809101010325406").is_err());
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(Profile::from_hex("0112080910").is_err());
+        assert!(Profile::from_hex("0904abcd").is_err());
+        assert!(Profile::from_hex("0704abcd0704abcd").is_err());
+        assert!(Profile::from_hex("").is_err());
+        assert!(Profile::from_hex(" \n").is_err());
+    }
+}
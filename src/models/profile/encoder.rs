@@ -1,7 +1,14 @@
 use super::Profile;
 use serde::Serialize;
-#[allow(dead_code)]
-enum Tags {
+use std::fmt::Write as _;
+use zeroize::Zeroizing;
+
+/// Room for every field of a profile, so that encoding it never grows a
+/// buffer and leaves a copy of its secrets behind on the heap.
+const HEX_CAPACITY: usize = 1024;
+const JSON_CAPACITY: usize = 4096;
+
+pub(super) enum Tags {
     Imsi = 1,
     Iccid = 2,
     Opc = 3,
@@ -19,26 +26,31 @@ enum Tags {
 struct AdditionField {
     name: String,
     file: String,
-    content: String,
+    content: Zeroizing<String>,
 }
 #[derive(Serialize)]
-struct ExtendedProfile {
-    profile: Profile,
+struct ExtendedProfile<'a> {
+    profile: &'a Profile,
     additional_fields: Vec<AdditionField>,
 }
 
 impl Profile {
-    pub fn to_json(&self, include_smsp: bool, include_smsc: bool) -> Result<String, Box<dyn std::error::Error>> {
+    pub fn to_json(&self, include_smsp: bool, include_smsc: bool) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
         to_json(self, include_smsp, include_smsc)
     }
-    pub fn to_hex(&self, include_smsp: bool, include_smsc: bool) -> String {
+    pub fn to_hex(&self, include_smsp: bool, include_smsc: bool) -> Zeroizing<String> {
         to_hex(self, include_smsp, include_smsc)
     }
+
+    /// The profile as JSON, the way it was decrypted.
+    pub fn to_raw_json(&self) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+        serialize(self)
+    }
 }
 
-fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<String, Box<dyn std::error::Error>> {
+fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
     let mut profile = ExtendedProfile {
-        profile: p.clone(),
+        profile: p,
         additional_fields: Vec::new(),
     };
 
@@ -46,7 +58,7 @@ fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<String
         let imsi = AdditionField {
             name: String::from("encoded imsi"),
             file: String::from("/3f00/7ff0/6f07"),
-            content: encode_imsi(i),
+            content: Zeroizing::new(encode_imsi(i)),
         };
 
         profile.additional_fields.push(imsi);
@@ -56,7 +68,7 @@ fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<String
         let iccid = AdditionField {
             name: String::from("encoded iccid"),
             file: String::from("/3f00/2fe2"),
-            content: swap_nibbles(i),
+            content: Zeroizing::new(swap_nibbles(i)),
         };
 
         profile.additional_fields.push(iccid);
@@ -66,7 +78,7 @@ fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<String
         let a001 = AdditionField {
             name: String::from("Key material for attaching to network"),
             file: String::from("/3f00/a001"),
-            content: format!("{}{}00", k, o),
+            content: concat(&[k, o, "00"]),
         };
 
         profile.additional_fields.push(a001);
@@ -76,7 +88,7 @@ fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<String
         let a004 = AdditionField {
             name: String::from("Key material for OTA related functions"),
             file: String::from("/3f00/a004"),
-            content: format!("b00011060101{}{}{}", kic, kid, rpad("", 2 * 76, None)),
+            content: concat(&["b00011060101", kic, kid, &rpad("", 2 * 76, None)]),
         };
 
         profile.additional_fields.push(a004);
@@ -88,12 +100,26 @@ fn to_json(p: &Profile, include_smsp: bool, include_smsc: bool) -> Result<String
         content: to_hex(p, include_smsp, include_smsc),
     });
 
-    let t = serde_json::to_string(&profile)?;
-    Ok(t)
+    serialize(&profile)
+}
+
+fn serialize<T: Serialize>(value: &T) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let mut json = Zeroizing::new(Vec::with_capacity(JSON_CAPACITY));
+    serde_json::to_writer(&mut *json, value)?;
+    Ok(Zeroizing::new(String::from_utf8(std::mem::take(&mut *json))?))
+}
+
+/// Concatenate `parts` into a buffer of the exact size.
+fn concat(parts: &[&str]) -> Zeroizing<String> {
+    let mut s = Zeroizing::new(String::with_capacity(parts.iter().map(|p| p.len()).sum()));
+    for part in parts {
+        s.push_str(part);
+    }
+    s
 }
 
-pub fn to_hex(p: &Profile, include_smsp: bool, include_smsc: bool) -> String {
-    let mut ret = String::new();
+pub fn to_hex(p: &Profile, include_smsp: bool, include_smsc: bool) -> Zeroizing<String> {
+    let mut ret = Zeroizing::new(String::with_capacity(HEX_CAPACITY));
 
     if let Some(imsi) = &p.imsi {
         let encoded_imsi = encode_imsi(imsi);
@@ -134,16 +160,16 @@ pub fn to_hex(p: &Profile, include_smsp: bool, include_smsc: bool) -> String {
     }
 
     if let Some(pin) = &p.pin {
-        let encoded_pin = hex::encode(pin.as_bytes());
+        let encoded_pin = Zeroizing::new(hex::encode(pin.as_bytes()));
         ret.push_str(&encoded_pin.encode_tlv(Tags::Pin));
     }
 
     if let Some(puk) = &p.puk {
-        let encoded_puk = hex::encode(puk.as_bytes());
+        let encoded_puk = Zeroizing::new(hex::encode(puk.as_bytes()));
         ret.push_str(&encoded_puk.encode_tlv(Tags::Puk));
     }
     if let Some(adm) = &p.adm {
-        let encoded_adm = hex::encode(adm.as_bytes());
+        let encoded_adm = Zeroizing::new(hex::encode(adm.as_bytes()));
         ret.push_str(&encoded_adm.encode_tlv(Tags::Adm));
     }
     ret
@@ -165,7 +191,7 @@ fn encode_imsi(imsi: &str) -> String {
     )
 }
 
-fn swap_nibbles(s: &str) -> String {
+pub(super) fn swap_nibbles(s: &str) -> String {
     if s.len() <= 1 {
         return s.to_string();
     }
@@ -182,7 +208,7 @@ fn half_round_up(n: usize) -> usize {
 }
 
 fn rpad(s: &str, l: usize, b: Option<u8>) -> String {
-    let padding_len = l - s.len();
+    let padding_len = l.saturating_sub(s.len());
     let pad = String::from_utf8(vec![b.unwrap_or(b'f'); padding_len]).unwrap();
     format!("{}{}", s, pad)
 }
@@ -219,12 +245,15 @@ fn encode_smsc(smsc: &str) -> String {
 }
 
 trait Tlv {
-    fn encode_tlv(&self, tag: Tags) -> String;
+    fn encode_tlv(&self, tag: Tags) -> Zeroizing<String>;
 }
 
-impl Tlv for String {
-    fn encode_tlv(&self, tag: Tags) -> String {
-        format!("{:02x}{:02x}{}", tag as u8, self.len(), self)
+impl Tlv for str {
+    fn encode_tlv(&self, tag: Tags) -> Zeroizing<String> {
+        let mut tlv = Zeroizing::new(String::with_capacity(4 + self.len()));
+        let _ = write!(tlv, "{:02x}{:02x}", tag as u8, self.len());
+        tlv.push_str(self);
+        tlv
     }
 }
 
@@ -270,10 +299,10 @@ mod tests {
         let p = Profile {
             iccid: Some(String::from("89000123456789012341")),
             imsi: Some(String::from("001010123456063")),
-            opc: Some(String::from("00000000000000000000000000000000")),
-            k: Some(String::from("000102030405060708090A0B0C0D0E0F")),
-            kic: Some(String::from("000102030405060708090A0B0C0D0E0F")),
-            kid: Some(String::from("000102030405060708090A0B0C0D0E0F")),
+            opc: Some("00000000000000000000000000000000".into()),
+            k: Some("000102030405060708090A0B0C0D0E0F".into()),
+            kic: Some("000102030405060708090A0B0C0D0E0F".into()),
+            kid: Some("000102030405060708090A0B0C0D0E0F".into()),
             pin: None,
             puk: None,
             adm: None,
@@ -289,7 +318,7 @@ mod tests {
             "98001032547698103214",
             swap_nibbles(p.iccid.as_deref().unwrap())
         );
-    assert_eq!(p.to_hex(true, false), "01120809101010325406360214980010325476981032140320000000000000000000000000000000000420000102030405060708090A0B0C0D0E0F0520000102030405060708090A0B0C0D0E0F0620000102030405060708090A0B0C0D0E0F")
+    assert_eq!(*p.to_hex(true, false), "01120809101010325406360214980010325476981032140320000000000000000000000000000000000420000102030405060708090A0B0C0D0E0F0520000102030405060708090A0B0C0D0E0F0620000102030405060708090A0B0C0D0E0F")
     }
 
     #[test]
@@ -0,0 +1,235 @@
+use super::Profile;
+use std::error::Error;
+use std::fmt;
+
+/// Everything that is wrong with a profile, one entry per problem.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid profile: {}", self.problems.join("; "))
+    }
+}
+
+impl Error for ValidationError {}
+
+impl Profile {
+    /// Check that every field present can be encoded without producing a
+    /// corrupt TLV. Missing fields are not an error, see `missing_fields`.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut problems = Vec::new();
+        let mut check = |name: &str, value: Option<&str>, f: fn(&str) -> Result<(), String>| {
+            if let Some(value) = value {
+                if let Err(e) = f(value) {
+                    problems.push(format!("{} {}", name, e));
+                }
+            }
+        };
+
+        check("ICCID", self.iccid.as_deref(), validate_iccid);
+        check("IMSI", self.imsi.as_deref(), validate_imsi);
+        check("K", self.k.as_deref(), validate_key);
+        check("OPc", self.opc.as_deref(), validate_key);
+        check("KIc", self.kic.as_deref(), validate_key);
+        check("KID", self.kid.as_deref(), validate_key);
+        check("PIN", self.pin.as_deref(), validate_pin);
+        check("PUK", self.puk.as_deref(), validate_puk);
+        check("ADM", self.adm.as_deref(), validate_adm);
+        check("SMSC", self.smsc.as_deref(), validate_smsc);
+        check("SMSP", self.smsp.as_deref(), validate_smsp);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { problems })
+        }
+    }
+}
+
+fn digits(s: &str) -> Result<(), String> {
+    match s.chars().find(|c| !c.is_ascii_digit()) {
+        Some(c) => Err(format!("contains non-digit '{}'", c)),
+        None => Ok(()),
+    }
+}
+
+fn length(s: &str, range: std::ops::RangeInclusive<usize>, unit: &str) -> Result<(), String> {
+    if range.contains(&s.len()) {
+        return Ok(());
+    }
+    if range.start() == range.end() {
+        Err(format!(
+            "must be {} {}, got {}",
+            range.start(),
+            unit,
+            s.len()
+        ))
+    } else {
+        Err(format!(
+            "must be {} to {} {}, got {}",
+            range.start(),
+            range.end(),
+            unit,
+            s.len()
+        ))
+    }
+}
+
+/// Luhn check over all digits, including the check digit itself.
+fn luhn(s: &str) -> bool {
+    let sum: u32 = s
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(i, d)| match (i % 2, d * 2) {
+            (1, doubled) if doubled > 9 => doubled - 9,
+            (1, doubled) => doubled,
+            _ => d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn validate_iccid(iccid: &str) -> Result<(), String> {
+    digits(iccid)?;
+    length(iccid, 19..=20, "digits")?;
+    if !luhn(iccid) {
+        return Err(format!("{} has an invalid Luhn check digit", iccid));
+    }
+    Ok(())
+}
+
+/// MCC, MNC and MSIN. The MSIN can be shorter than the 9 or 10 digits
+/// that make up the usual 15.
+fn validate_imsi(imsi: &str) -> Result<(), String> {
+    digits(imsi)?;
+    length(imsi, 6..=15, "digits")
+}
+
+/// K, OPc, KIc and KID are all 128 bit keys.
+fn validate_key(key: &str) -> Result<(), String> {
+    length(key, 32..=32, "hex characters")?;
+    match key.chars().find(|c| !c.is_ascii_hexdigit()) {
+        Some(c) => Err(format!("contains non-hex character '{}'", c)),
+        None => Ok(()),
+    }
+}
+
+fn validate_pin(pin: &str) -> Result<(), String> {
+    digits(pin)?;
+    length(pin, 4..=8, "digits")
+}
+
+fn validate_puk(puk: &str) -> Result<(), String> {
+    digits(puk)?;
+    length(puk, 8..=8, "digits")
+}
+
+fn validate_adm(adm: &str) -> Result<(), String> {
+    if !adm.chars().all(|c| c.is_ascii_graphic()) {
+        return Err("must be printable ASCII".into());
+    }
+    length(adm, 8..=8, "characters")
+}
+
+/// International number, with at most the 20 digits that fit the 12 octet
+/// SMSC field.
+fn validate_smsc(smsc: &str) -> Result<(), String> {
+    let number = smsc.strip_prefix('+').unwrap_or(smsc);
+    digits(number)?;
+    length(number, 1..=20, "digits")
+}
+
+fn validate_smsp(smsp: &str) -> Result<(), String> {
+    if !smsp.len().is_multiple_of(2) || !smsp.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("must be an even number of hex characters".into());
+    }
+    // the TLV length is a single octet counting hex characters
+    length(smsp, 2..=254, "hex characters")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_profile() -> Profile {
+        Profile {
+            iccid: Some(String::from("89000123456789012341")),
+            imsi: Some(String::from("001010123456063")),
+            opc: Some("00000000000000000000000000000000".into()),
+            k: Some("000102030405060708090A0B0C0D0E0F".into()),
+            kic: Some("000102030405060708090A0B0C0D0E0F".into()),
+            kid: Some("000102030405060708090A0B0C0D0E0F".into()),
+            pin: Some("1234".into()),
+            puk: Some("12345678".into()),
+            adm: Some("12345678".into()),
+            smsp: Some(String::from("abcd")),
+            smsc: Some(String::from("+447797704848")),
+        }
+    }
+
+    #[test]
+    fn test_valid_profile() {
+        valid_profile().validate().unwrap();
+    }
+
+    #[test]
+    fn test_luhn() {
+        assert!(luhn("89457300000013500452"));
+        assert!(luhn("89457300000013500460"));
+        assert!(!luhn("89457300000013500453"));
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let mut p = valid_profile();
+        p.iccid = Some(String::from("89000123456789012342"));
+        p.imsi = Some(String::from("00101"));
+        p.k = Some("000102030405060708090A0B0C0D0E0".into());
+        p.opc = Some("0000000000000000000000000000000g".into());
+        p.pin = Some("12".into());
+        p.smsc = Some(String::from("+44 7797 704848"));
+
+        let err = p.validate().unwrap_err();
+        assert_eq!(
+            err.problems,
+            vec![
+                "ICCID 89000123456789012342 has an invalid Luhn check digit",
+                "IMSI must be 6 to 15 digits, got 5",
+                "K must be 32 hex characters, got 31",
+                "OPc contains non-hex character 'g'",
+                "PIN must be 4 to 8 digits, got 2",
+                "SMSC contains non-digit ' '",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_short_imsi() {
+        let mut p = valid_profile();
+        for imsi in [
+            "001010",
+            "0010101",
+            "001010123456",
+            "0010101234560",
+            "00101012345606",
+        ] {
+            p.imsi = Some(String::from(imsi));
+            p.validate().unwrap();
+
+            let decoded = Profile::from_hex(&p.to_hex(false, false)).unwrap();
+            assert_eq!(decoded.imsi, p.imsi, "{}", imsi);
+        }
+    }
+
+    #[test]
+    fn test_long_imsi_does_not_reach_encoder() {
+        let mut p = valid_profile();
+        p.imsi = Some(String::from("0010101234560631"));
+        assert!(p.validate().is_err());
+    }
+}
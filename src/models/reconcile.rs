@@ -0,0 +1,245 @@
+use super::audit::{Event, Record};
+use super::profile::api::Sim;
+use super::store::Inventory;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+/// How the use of a pool recorded locally compares to the SIMs active on
+/// the platform. All lists are sorted.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Handed out here, but not active on the platform.
+    pub used_not_active: Vec<String>,
+    /// Active on the platform, but still unused in the pool.
+    pub active_not_used: Vec<String>,
+    /// Neither handed out nor active.
+    pub never_used: Vec<String>,
+    /// Handed out and active, as expected.
+    pub matched: usize,
+    /// Active ICCIDs this pool has never held, e.g. from another pool.
+    pub unknown: usize,
+}
+
+impl Report {
+    pub fn discrepancies(&self) -> usize {
+        self.used_not_active.len() + self.active_not_used.len()
+    }
+}
+
+/// ICCIDs handed out according to the audit log and not returned since.
+pub fn issued(records: &[Record]) -> BTreeSet<String> {
+    let mut issued = BTreeSet::new();
+    for record in records {
+        match &record.event {
+            Event::Issue { iccid, .. } => {
+                issued.insert(iccid.clone());
+            }
+            Event::Release { iccid, .. } => {
+                issued.remove(iccid);
+            }
+            Event::Abort { iccids, .. } | Event::Expire { iccids, .. } => {
+                for iccid in iccids {
+                    issued.remove(iccid);
+                }
+            }
+            Event::Confirm { .. }
+            | Event::Commit { .. }
+            | Event::Quarantine { .. }
+            | Event::Rekey { .. } => {}
+        }
+    }
+    issued
+}
+
+/// Compare the profiles of a pool with the ICCIDs active on the platform.
+/// A profile counts as used if it is consumed in the store or handed out
+/// according to the audit log.
+pub fn compare(
+    inventory: &Inventory,
+    issued: &BTreeSet<String>,
+    active: &BTreeSet<String>,
+) -> Report {
+    let mut report = Report::default();
+    let used: BTreeSet<&String> = inventory.consumed.iter().chain(issued).collect();
+
+    for iccid in &used {
+        if active.contains(*iccid) {
+            report.matched += 1;
+        } else {
+            report.used_not_active.push(iccid.to_string());
+        }
+    }
+    for iccid in &inventory.available {
+        if used.contains(iccid) {
+            continue;
+        }
+        if active.contains(iccid) {
+            report.active_not_used.push(iccid.clone());
+        } else {
+            report.never_used.push(iccid.clone());
+        }
+    }
+    report.unknown = active
+        .iter()
+        .filter(|i| !used.contains(i) && inventory.available.binary_search(i).is_err())
+        .count();
+
+    report
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Exported {
+    Iccids(Vec<String>),
+    Sims(Vec<Sim>),
+    Response { sims: Vec<Sim> },
+}
+
+/// Read the ICCIDs exported from the platform. Either a JSON array of
+/// ICCIDs or SIM objects, a saved response of the SIMs API, a CSV with an
+/// `iccid` column, or one ICCID per line. SIMs marked as not activated are
+/// left out.
+pub fn read_active(path: &Path) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+    if content.trim_start().starts_with(['[', '{']) {
+        let sims = match serde_json::from_str(&content)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        {
+            Exported::Iccids(iccids) => return Ok(iccids.into_iter().collect()),
+            Exported::Sims(sims) | Exported::Response { sims } => sims,
+        };
+        return Ok(sims
+            .into_iter()
+            .filter(Sim::is_active)
+            .map(|s| s.iccid)
+            .collect());
+    }
+
+    let mut lines = content.lines().map(str::trim).filter(|l| !l.is_empty());
+    let Some(first) = lines.next() else {
+        return Ok(BTreeSet::new());
+    };
+    let header: Vec<String> = first
+        .split(',')
+        .map(|c| c.trim().trim_matches('"').to_lowercase())
+        .collect();
+
+    let Some(column) = header.iter().position(|c| c == "iccid") else {
+        // a plain list. Anything else is most likely a CSV without the
+        // column, which would otherwise report every SIM as a discrepancy
+        return std::iter::once(first)
+            .chain(lines)
+            .enumerate()
+            .map(|(n, line)| {
+                if line.chars().all(|c| c.is_ascii_digit()) {
+                    Ok(line.to_string())
+                } else {
+                    Err(format!(
+                        "{}:{} is not an ICCID. A CSV needs an iccid column",
+                        path.display(),
+                        n + 1
+                    )
+                    .into())
+                }
+            })
+            .collect();
+    };
+    lines
+        .enumerate()
+        .map(|(n, line)| {
+            line.split(',')
+                .nth(column)
+                .map(|c| c.trim().trim_matches('"').to_string())
+                .ok_or_else(|| format!("{}:{} has no iccid column", path.display(), n + 2).into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(iccids: &[&str]) -> BTreeSet<String> {
+        iccids.iter().map(|i| i.to_string()).collect()
+    }
+
+    fn record(event: Event) -> Record {
+        Record::new(event)
+    }
+
+    #[test]
+    fn test_issued_follows_returns() {
+        let issue = |iccid: &str| Event::Issue {
+            iccid: iccid.into(),
+            imsi: None,
+            serial: None,
+            device_id: None,
+            format: "hex".into(),
+            token: None,
+        };
+        let records = vec![
+            record(issue("001")),
+            record(issue("002")),
+            record(issue("003")),
+            record(Event::Release {
+                iccid: "002".into(),
+                by: "me".into(),
+                reason: "bad board".into(),
+            }),
+            record(Event::Abort {
+                token: "t".into(),
+                iccids: vec!["003".into()],
+            }),
+        ];
+        assert_eq!(issued(&records), set(&["001"]));
+    }
+
+    #[test]
+    fn test_compare() {
+        let inventory = Inventory {
+            available: vec!["003".into(), "004".into(), "005".into()],
+            consumed: vec!["001".into(), "002".into()],
+        };
+        let report = compare(
+            &inventory,
+            &set(&["003"]),
+            &set(&["001", "003", "004", "999"]),
+        );
+
+        assert_eq!(report.used_not_active, vec!["002"]);
+        assert_eq!(report.active_not_used, vec!["004"]);
+        assert_eq!(report.never_used, vec!["005"]);
+        assert_eq!(report.matched, 2);
+        assert_eq!(report.unknown, 1);
+        assert_eq!(report.discrepancies(), 2);
+    }
+
+    #[test]
+    fn test_read_active_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("active");
+        let expected = set(&["001", "002"]);
+
+        for content in [
+            "001\n002\n",
+            "id,ICCID,label\n1,001,a\n2,\"002\",b\n",
+            r#"["001","002"]"#,
+            r#"[{"iccid":"001"},{"iccid":"002","activated":true}]"#,
+            r#"{"sims":[{"iccid":"001"},{"iccid":"002"},{"iccid":"003","activated":false}]}"#,
+        ] {
+            std::fs::write(&path, content).unwrap();
+            assert_eq!(read_active(&path).unwrap(), expected, "{}", content);
+        }
+
+        std::fs::write(&path, "id,iccid\n1\n").unwrap();
+        assert!(read_active(&path).is_err());
+        std::fs::write(&path, "id,sim\n1,001\n").unwrap();
+        assert!(read_active(&path).is_err());
+        std::fs::write(&path, "001\n002 x\n").unwrap();
+        assert!(read_active(&path).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use super::pool::Lock;
+use super::profile::crypto::{Key, Padding, PublicKey};
+use super::profile::{EncryptedProfile, Profile};
+use super::store::Store;
+use std::error::Error;
+
+/// Profiles of a pool encrypted to a new key, not written yet.
+#[derive(Debug, Default)]
+pub struct Rekeyed {
+    pub profiles: Vec<EncryptedProfile>,
+    /// ICCIDs that couldn't be re-encrypted, with the reason.
+    pub failed: Vec<(String, String)>,
+    /// ICCIDs already encrypted to the new key, e.g. by an earlier rekey
+    /// that didn't get to finish. The old key doesn't decrypt them.
+    pub current: Vec<String>,
+}
+
+/// Decrypt every profile in `store`, consumed or not, with `old` and encrypt
+/// it to `new` with `padding`. The decrypted profile is encrypted again byte for byte, so
+/// fields this version doesn't know about survive. Profiles whose envelope
+/// names `new` as their key are left as they are, unless `old` still
+/// decrypts them: the envelope isn't authenticated. Nothing is written.
+pub fn reencrypt(
+    store: &dyn Store,
+    old: &Key,
+    new: &PublicKey,
+    padding: Padding,
+    _lock: &Lock,
+) -> Result<Rekeyed, Box<dyn Error>> {
+    let inventory = store.inventory()?;
+    let mut iccids: Vec<String> = inventory
+        .available
+        .into_iter()
+        .chain(inventory.consumed)
+        .collect();
+    iccids.sort();
+
+    let fingerprint = new.fingerprint()?;
+    let mut rekeyed = Rekeyed::default();
+    for iccid in iccids {
+        let Some(profile) = store.get(&iccid)? else {
+            continue;
+        };
+        if profile.key() == Some(fingerprint.as_str()) && old.decrypt_raw(&profile).is_err() {
+            rekeyed.current.push(iccid);
+            continue;
+        }
+        match reencrypt_one(&profile, old, new, padding) {
+            Ok(profile) => rekeyed.profiles.push(profile),
+            Err(e) => rekeyed.failed.push((iccid, e.to_string())),
+        }
+    }
+    Ok(rekeyed)
+}
+
+fn reencrypt_one(
+    profile: &EncryptedProfile,
+    old: &Key,
+    new: &PublicKey,
+    padding: Padding,
+) -> Result<EncryptedProfile, Box<dyn Error>> {
+    let plaintext = old
+        .decrypt_raw(profile)
+        .map_err(|e| format!("undecryptable: {}", e))?;
+
+    // only hand on what `next` can use
+    let decrypted: Profile =
+        serde_json::from_slice(&plaintext).map_err(|e| format!("not a profile: {}", e))?;
+    if let Some(inner) = &decrypted.iccid {
+        if inner != profile.iccid() {
+            return Err(format!(
+                "ICCID mismatch: envelope {} profile {}",
+                profile.iccid(),
+                inner
+            )
+            .into());
+        }
+    }
+
+    new.encrypt(profile.iccid(), &plaintext, padding)
+}
+
+/// Write the re-encrypted profiles, all of them or none.
+pub fn apply(
+    store: &dyn Store,
+    profiles: &[EncryptedProfile],
+    lock: &Lock,
+) -> Result<(), Box<dyn Error>> {
+    store
+        .replace_all(profiles, lock)
+        .map_err(|e| format!("Failed to write the rekeyed profiles: {}", e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pool;
+    use crate::models::profile::api::Response;
+    use crate::models::secret::Secret;
+    use crate::models::store::dir::DirStore;
+    use std::path::PathBuf;
+
+    fn test_pool() -> tempfile::TempDir {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/response.json");
+        let response: Response =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        for profile in &response.profiles {
+            std::fs::write(
+                dir.path().join(format!("{}.json", profile.iccid())),
+                serde_json::to_string(profile).unwrap(),
+            )
+            .unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_rekey() {
+        let dir = test_pool();
+        let store = DirStore::new(dir.path());
+        pool::claim(&store, 1).unwrap();
+
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/key");
+        let old = Key::new(&path, &Secret::Prompt).unwrap();
+        let new = Key::generate(1024).unwrap();
+
+        let lock = Lock::acquire(dir.path()).unwrap();
+        let rekeyed =
+            reencrypt(&store, &old, &new.public_key(), Padding::OaepSha256, &lock).unwrap();
+        assert!(rekeyed.failed.is_empty());
+        assert_eq!(rekeyed.profiles.len(), 2);
+        apply(&store, &rekeyed.profiles, &lock).unwrap();
+
+        // the consumed profile stays consumed
+        assert!(dir.path().join("__001.json").exists());
+        for iccid in ["001", "002"] {
+            let profile = store.get(iccid).unwrap().unwrap();
+            assert!(old.decrypt(&profile).is_err());
+            assert!(new.decrypt(&profile).is_ok());
+        }
+
+        // a second run leaves the rekeyed profiles alone
+        let again = reencrypt(&store, &old, &new.public_key(), Padding::OaepSha256, &lock).unwrap();
+        assert!(again.failed.is_empty());
+        assert!(again.profiles.is_empty());
+        assert_eq!(again.current, vec!["001", "002"]);
+    }
+
+    #[test]
+    fn test_key_field_alone_doesnt_skip() {
+        let dir = test_pool();
+        let store = DirStore::new(dir.path());
+
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/key");
+        let old = Key::new(&path, &Secret::Prompt).unwrap();
+        let new = Key::generate(1024).unwrap().public_key();
+
+        // still encrypted to the old key, whatever the envelope says
+        let file = dir.path().join("001.json");
+        let mut profile: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        profile["key"] = new.fingerprint().unwrap().into();
+        std::fs::write(&file, profile.to_string()).unwrap();
+
+        let lock = Lock::acquire(dir.path()).unwrap();
+        let rekeyed = reencrypt(&store, &old, &new, Padding::OaepSha256, &lock).unwrap();
+        assert!(rekeyed.current.is_empty());
+        assert_eq!(rekeyed.profiles.len(), 2);
+    }
+
+    #[test]
+    fn test_apply_is_all_or_nothing() {
+        let dir = test_pool();
+        let store = DirStore::new(dir.path());
+        let before = std::fs::read_to_string(dir.path().join("001.json")).unwrap();
+
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/key");
+        let old = Key::new(&path, &Secret::Prompt).unwrap();
+        let new = Key::generate(1024).unwrap().public_key();
+        let lock = Lock::acquire(dir.path()).unwrap();
+        let rekeyed = reencrypt(&store, &old, &new, Padding::OaepSha1, &lock).unwrap();
+
+        // the second profile is gone by the time the rekey is written
+        std::fs::remove_file(dir.path().join("002.json")).unwrap();
+        assert!(apply(&store, &rekeyed.profiles, &lock).is_err());
+
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("001.json")).unwrap(),
+            before
+        );
+        let mut left: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec![pool::LOCK_FILE, "001.json"]);
+    }
+}
@@ -0,0 +1,310 @@
+use super::audit;
+use super::pool::{self, Lock};
+use super::store::{State, Store};
+use chrono::{DateTime, Utc};
+use rsa::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Extension of the files holding open reservations, next to the profiles.
+pub const RESERVATION_EXTENSION: &str = "reservation";
+
+/// Profiles handed out by `next --reserve` that are waiting for `commit` or `abort`.
+///
+/// The profiles are claimed like any other handed out profile, so no other
+/// station can pick them while the reservation is open.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reservation {
+    pub token: String,
+    pub iccids: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+impl Reservation {
+    pub fn create(
+        base_path: &Path,
+        iccids: Vec<String>,
+        ttl: Duration,
+    ) -> Result<Reservation, Box<dyn Error>> {
+        let mut token = [0u8; 16];
+        OsRng.fill_bytes(&mut token);
+
+        let created = Utc::now();
+        let reservation = Reservation {
+            token: hex::encode(token),
+            iccids,
+            created,
+            expires: created + chrono::Duration::from_std(ttl)?,
+        };
+
+        reservation.save(base_path)?;
+        Ok(reservation)
+    }
+
+    fn save(&self, base_path: &Path) -> Result<(), Box<dyn Error>> {
+        let serialized = serde_json::to_string(self)?;
+        super::fs::write_atomic(&path(base_path, &self.token)?, serialized.as_bytes())
+    }
+
+    fn load(base_path: &Path, token: &str) -> Result<Reservation, Box<dyn Error>> {
+        let path = path(base_path, token)?;
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                format!("Unknown or expired reservation {}", token)
+            } else {
+                format!("Failed to read reservation {}: {}", path.display(), e)
+            }
+        })?;
+
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    fn remove(&self, base_path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::remove_file(path(base_path, &self.token)?)?;
+        Ok(())
+    }
+}
+
+fn path(base_path: &Path, token: &str) -> Result<PathBuf, Box<dyn Error>> {
+    // the token ends up in a file name
+    if token.is_empty() || !token.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid reservation token '{}'", token).into());
+    }
+    Ok(base_path.join(format!("{}.{}", token, RESERVATION_EXTENSION)))
+}
+
+/// All open reservations, including expired ones not yet swept. Files that
+/// can't be read are logged and left out, so one corrupt file doesn't stop
+/// every station sharing the pool.
+pub fn all(base_path: &Path) -> Result<Vec<Reservation>, Box<dyn Error>> {
+    let mut reservations = Vec::new();
+    for entry in std::fs::read_dir(base_path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == RESERVATION_EXTENSION) {
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|c| serde_json::from_str(&c).map_err(|e| e.to_string()));
+            match parsed {
+                Ok(reservation) => reservations.push(reservation),
+                Err(e) => log::error!(
+                    "Ignoring reservation {}. Is the file corrupted? {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+    Ok(reservations)
+}
+
+/// Token of the open reservation holding `iccid`, if any.
+pub fn token_of(base_path: &Path, iccid: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(all(base_path)?
+        .into_iter()
+        .find(|r| r.iccids.iter().any(|i| i == iccid))
+        .map(|r| r.token))
+}
+
+/// Mark the reserved profiles as provisioned. Can be repeated after a commit
+/// that failed half way.
+pub fn commit(store: &dyn Store, token: &str) -> Result<Reservation, Box<dyn Error>> {
+    let lock = Lock::acquire(store.dir())?;
+    let reservation = Reservation::load(store.dir(), token)?;
+
+    for iccid in &reservation.iccids {
+        if store.state(iccid)? == Some(State::Provisioned) {
+            continue;
+        }
+        pool::mark_provisioned(store, iccid, &lock)?;
+    }
+    reservation.remove(store.dir())?;
+    audit::append(
+        store.dir(),
+        audit::Event::Commit {
+            token: reservation.token.clone(),
+            iccids: reservation.iccids.clone(),
+        },
+        &lock,
+    )?;
+
+    Ok(reservation)
+}
+
+/// Return the reserved profiles to the pool.
+pub fn abort(store: &dyn Store, token: &str) -> Result<Reservation, Box<dyn Error>> {
+    let lock = Lock::acquire(store.dir())?;
+    let reservation = Reservation::load(store.dir(), token)?;
+
+    release(store, &reservation, &lock)?;
+    audit::append(
+        store.dir(),
+        audit::Event::Abort {
+            token: reservation.token.clone(),
+            iccids: reservation.iccids.clone(),
+        },
+        &lock,
+    )?;
+    Ok(reservation)
+}
+
+/// Abort every reservation past its expiry time. A reservation that can't
+/// be returned, e.g. one committed half way, is logged and left for an
+/// operator instead of failing the claim that swept it.
+pub fn expire(store: &dyn Store, lock: &Lock) -> Result<Vec<Reservation>, Box<dyn Error>> {
+    let now = Utc::now();
+    let mut expired = Vec::new();
+
+    for reservation in all(store.dir())? {
+        if reservation.expires > now {
+            continue;
+        }
+        match release(store, &reservation, lock) {
+            Ok(()) => expired.push(reservation),
+            Err(e) => log::error!(
+                "Failed to return expired reservation {}: {}. Commit or abort it",
+                reservation.token,
+                e
+            ),
+        }
+    }
+    Ok(expired)
+}
+
+/// Return the profiles of a reservation to the pool and remove it. If one
+/// can't be returned, the reservation is left holding only the profiles
+/// still out, so a later commit doesn't provision the ones back in the pool.
+fn release(
+    store: &dyn Store,
+    reservation: &Reservation,
+    lock: &Lock,
+) -> Result<(), Box<dyn Error>> {
+    for (i, iccid) in reservation.iccids.iter().enumerate() {
+        if let Err(e) = pool::unclaim(store, iccid, lock) {
+            if i > 0 {
+                Reservation {
+                    token: reservation.token.clone(),
+                    iccids: reservation.iccids[i..].to_vec(),
+                    created: reservation.created,
+                    expires: reservation.expires,
+                }
+                .save(store.dir())?;
+            }
+            return Err(e);
+        }
+    }
+    reservation.remove(store.dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pool::tests::pool_with;
+    use crate::models::store::dir::DirStore;
+
+    fn reserve(store: &dyn Store, count: usize, ttl: Duration) -> Reservation {
+        let iccids = pool::claim(store, count)
+            .unwrap()
+            .iter()
+            .map(|c| c.iccid().clone())
+            .collect();
+        Reservation::create(store.dir(), iccids, ttl).unwrap()
+    }
+
+    #[test]
+    fn test_commit() {
+        let dir = pool_with(2);
+        let store = DirStore::new(dir.path());
+        let reservation = reserve(&store, 2, Duration::from_secs(60));
+
+        assert!(pool::release(&store, "000", "me", "test").is_err());
+        commit(&store, &reservation.token).unwrap();
+        assert_eq!(store.state("000").unwrap(), Some(State::Provisioned));
+        assert_eq!(store.state("001").unwrap(), Some(State::Provisioned));
+        assert!(commit(&store, &reservation.token).is_err());
+        assert!(abort(&store, &reservation.token).is_err());
+    }
+
+    #[test]
+    fn test_abort() {
+        let dir = pool_with(1);
+        let store = DirStore::new(dir.path());
+        let reservation = reserve(&store, 1, Duration::from_secs(60));
+
+        abort(&store, &reservation.token).unwrap();
+        assert!(dir.path().join("000.json").exists());
+        assert!(all(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expired_reservation_is_returned_on_next_claim() {
+        let dir = pool_with(1);
+        let store = DirStore::new(dir.path());
+        let reservation = reserve(&store, 1, Duration::ZERO);
+
+        let claims = pool::claim(&store, 1).unwrap();
+        assert_eq!(claims[0].iccid(), "000");
+        assert!(commit(&store, &reservation.token).is_err());
+        assert!(
+            std::fs::read_to_string(dir.path().join(super::super::audit::AUDIT_FILE))
+                .unwrap()
+                .contains("\"event\":\"expire\"")
+        );
+    }
+
+    #[test]
+    fn test_commit_half_done() {
+        let dir = pool_with(3);
+        let store = DirStore::new(dir.path());
+        let reservation = reserve(&store, 2, Duration::ZERO);
+        assert!(pool::confirm(&store, "000", "me").is_err());
+
+        // as if a commit failed after the first profile
+        let lock = Lock::acquire(dir.path()).unwrap();
+        pool::mark_provisioned(&store, "000", &lock).unwrap();
+        drop(lock);
+
+        // expired, but can't be returned. The claim isn't held up by it
+        assert_eq!(pool::claim(&store, 1).unwrap()[0].iccid(), "002");
+        assert_eq!(all(dir.path()).unwrap().len(), 1);
+
+        commit(&store, &reservation.token).unwrap();
+        assert_eq!(store.state("001").unwrap(), Some(State::Provisioned));
+        assert!(all(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_abort_half_done() {
+        let dir = pool_with(2);
+        let store = DirStore::new(dir.path());
+        let reservation = reserve(&store, 2, Duration::from_secs(60));
+
+        let lock = Lock::acquire(dir.path()).unwrap();
+        pool::mark_provisioned(&store, "001", &lock).unwrap();
+        drop(lock);
+
+        // 000 went back to the pool and is no longer part of the reservation
+        assert!(abort(&store, &reservation.token).is_err());
+        assert_eq!(store.state("000").unwrap(), Some(State::Available));
+        assert_eq!(all(dir.path()).unwrap()[0].iccids, vec!["001"]);
+    }
+
+    #[test]
+    fn test_corrupt_reservation_is_ignored() {
+        let dir = pool_with(1);
+        let store = DirStore::new(dir.path());
+        std::fs::write(dir.path().join("abcd.reservation"), "{").unwrap();
+
+        assert!(all(dir.path()).unwrap().is_empty());
+        assert_eq!(pool::claim(&store, 1).unwrap()[0].iccid(), "000");
+        pool::release(&store, "000", "me", "test").unwrap();
+    }
+
+    #[test]
+    fn test_rejects_path_in_token() {
+        let dir = pool_with(0);
+        assert!(abort(&DirStore::new(dir.path()), "../../etc/passwd").is_err());
+    }
+}
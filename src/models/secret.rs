@@ -0,0 +1,331 @@
+use super::profile::SecretString;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where to read a secret from, such as the API key or the passphrase of a
+/// private key. Written as `prompt`, `env:VAR`, `file:PATH` (`file:-` for
+/// stdin), `fd:N`, `cmd:COMMAND` or `vault:PATH#FIELD`.
+///
+/// Only the first line is used, wherever the secret comes from, the way
+/// `pass` stores passwords.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Secret {
+    /// Ask on the terminal.
+    Prompt,
+    /// Read from the named environment variable.
+    Env(String),
+    /// Read from a file, or from stdin for `-`.
+    File(PathBuf),
+    /// Read from an open file descriptor, e.g. `3< secret`.
+    Fd(u32),
+    /// Run through the shell and read its output, e.g. `pass show softsim/api-key`.
+    Command(String),
+    /// Read a field of a secret from the Vault at `VAULT_ADDR`, with the
+    /// token in `VAULT_TOKEN` or `~/.vault-token`. KV version 1 and 2.
+    Vault { path: String, field: String },
+}
+
+/// Secrets shorter than this are masked completely.
+const MIN_PARTIAL_MASK: usize = 16;
+
+/// There is no `/dev/fd` to read an inherited descriptor from elsewhere.
+const FD_UNSUPPORTED: &str = "reading a secret from a file descriptor is only supported on Unix";
+
+/// Vault of a development server, or an agent listening locally.
+const DEFAULT_VAULT_ADDR: &str = "http://127.0.0.1:8200";
+
+impl Secret {
+    /// Read the secret. `what` names it in prompts and errors.
+    pub fn read(&self, what: &str) -> Result<SecretString, Box<dyn Error>> {
+        let secret = match self {
+            Secret::Prompt => rpassword::prompt_password(format!("{}: ", what))?,
+            Secret::Env(var) => first_line(
+                std::env::var(var)
+                    .map_err(|_| format!("Environment variable {} is not set", var))?,
+            ),
+            Secret::File(path) if path.as_os_str() == "-" => {
+                first_line(std::io::read_to_string(std::io::stdin())?)
+            }
+            Secret::File(path) => first_line(read_file(path, what)?),
+            #[cfg(unix)]
+            Secret::Fd(fd) => first_line(read_file(format!("/dev/fd/{}", fd), what)?),
+            #[cfg(not(unix))]
+            Secret::Fd(_) => return Err(FD_UNSUPPORTED.into()),
+            Secret::Command(command) => first_line(run(command)?),
+            Secret::Vault { path, field } => {
+                // reqwest's blocking client refuses to run on the async runtime
+                let (path, field) = (path.clone(), field.clone());
+                let lookup = move || vault(&path, &field, |var| std::env::var(var).ok());
+                first_line(
+                    std::thread::spawn(move || lookup().map_err(|e| e.to_string()))
+                        .join()
+                        .map_err(|_| "Vault lookup panicked")??,
+                )
+            }
+        };
+
+        if secret.is_empty() {
+            return Err(format!("{} from {} is empty", what, self).into());
+        }
+        Ok(SecretString::from(secret))
+    }
+}
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "prompt" {
+            return Ok(Secret::Prompt);
+        }
+        let Some((scheme, rest)) = s.split_once(':') else {
+            return Err(String::from(
+                "expected prompt, env:VAR, file:PATH, fd:N, cmd:COMMAND or vault:PATH#FIELD. \
+                 Secrets themselves don't belong on the command line, it ends up in the shell history",
+            ));
+        };
+        match scheme {
+            "env" => Ok(Secret::Env(rest.to_string())),
+            "file" => Ok(Secret::File(PathBuf::from(rest))),
+            "fd" if cfg!(not(unix)) => Err(String::from(FD_UNSUPPORTED)),
+            "fd" => rest
+                .parse()
+                .map(Secret::Fd)
+                .map_err(|_| format!("'{}' is not a file descriptor", rest)),
+            "cmd" => Ok(Secret::Command(rest.to_string())),
+            "vault" => match rest.split_once('#') {
+                Some((path, field)) if !path.is_empty() && !field.is_empty() => Ok(Secret::Vault {
+                    path: path.trim_matches('/').to_string(),
+                    field: field.to_string(),
+                }),
+                _ => Err(String::from("expected vault:PATH#FIELD")),
+            },
+            _ => Err(format!("unknown secret source '{}:'", scheme)),
+        }
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Prompt => write!(f, "prompt"),
+            Secret::Env(var) => write!(f, "env:{}", var),
+            Secret::File(path) => write!(f, "file:{}", path.display()),
+            Secret::Fd(fd) => write!(f, "fd:{}", fd),
+            Secret::Command(command) => write!(f, "cmd:{}", command),
+            Secret::Vault { path, field } => write!(f, "vault:{}#{}", path, field),
+        }
+    }
+}
+
+// a command may carry a key inline, e.g. `cmd:echo KEY`
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Command(command) => write!(f, "Command({:?})", mask(command)),
+            _ => write!(f, "{:?}", self.to_string()),
+        }
+    }
+}
+
+impl From<Secret> for String {
+    fn from(secret: Secret) -> String {
+        secret.to_string()
+    }
+}
+
+/// Show only the first and last two characters of a secret, e.g. `00****0F`,
+/// for logs and output meant for people. Short secrets are masked completely.
+pub fn mask(secret: &str) -> String {
+    let len = secret.chars().count();
+    if len < MIN_PARTIAL_MASK {
+        return "*".repeat(len);
+    }
+    let head: String = secret.chars().take(2).collect();
+    let tail: String = secret.chars().skip(len - 2).collect();
+    format!("{}{}{}", head, "*".repeat(len - 4), tail)
+}
+
+fn read_file<P: AsRef<Path>>(path: P, what: &str) -> Result<String, Box<dyn Error>> {
+    let path = path.as_ref();
+    std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {} from {}: {}", what, path.display(), e).into())
+}
+
+fn first_line(content: String) -> String {
+    content.lines().next().unwrap_or("").to_string()
+}
+
+fn run(command: &str) -> Result<String, Box<dyn Error>> {
+    #[cfg(windows)]
+    let mut shell = std::process::Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    #[cfg(not(windows))]
+    let mut shell = std::process::Command::new("sh");
+    #[cfg(not(windows))]
+    shell.arg("-c");
+
+    // stderr stays on the terminal, e.g. for a gpg pinentry
+    let output = shell
+        .arg(command)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {}", command, e))?;
+    if !output.status.success() {
+        return Err(format!("'{}' failed with {}", command, output.status).into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Read `field` of the secret at `path`, with `var` looking up `VAULT_ADDR`
+/// and `VAULT_TOKEN`.
+fn vault(
+    path: &str,
+    field: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<String, Box<dyn Error>> {
+    let addr = var("VAULT_ADDR").unwrap_or_else(|| DEFAULT_VAULT_ADDR.to_string());
+    let token = match var("VAULT_TOKEN") {
+        Some(token) => token,
+        None => {
+            let path = dirs::home_dir()
+                .ok_or("VAULT_TOKEN is not set")?
+                .join(".vault-token");
+            std::fs::read_to_string(&path)
+                .map_err(|_| "VAULT_TOKEN is not set and there is no ~/.vault-token")?
+                .trim()
+                .to_string()
+        }
+    };
+
+    let url = format!("{}/v1/{}", addr.trim_end_matches('/'), path);
+    let response = reqwest::blocking::Client::new()
+        .get(&url)
+        .header("X-Vault-Token", token)
+        .send()
+        .map_err(|e| format!("Failed to reach Vault at {}: {}", addr, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Vault answered {} for {}", response.status(), path).into());
+    }
+
+    let body: serde_json::Value = response.json()?;
+    // KV version 2 nests the secret one level deeper than version 1
+    let data = &body["data"];
+    let data = if data["data"].is_object() {
+        &data["data"]
+    } else {
+        data
+    };
+    match data[field].as_str() {
+        Some(value) => Ok(value.to_string()),
+        None => Err(format!("Vault secret {} has no field {}", path, field).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_parse() {
+        for spec in [
+            "prompt",
+            "env:SOFTSIM_API_KEY",
+            "file:/etc/softsim/key",
+            #[cfg(unix)]
+            "fd:3",
+            "cmd:pass show softsim/api-key",
+            "vault:secret/data/softsim#api_key",
+        ] {
+            assert_eq!(spec.parse::<Secret>().unwrap().to_string(), spec);
+        }
+
+        assert!("0123456789abcdef".parse::<Secret>().is_err());
+        assert!("fd:x".parse::<Secret>().is_err());
+        #[cfg(not(unix))]
+        assert!("fd:3".parse::<Secret>().is_err());
+        assert!("vault:secret/softsim".parse::<Secret>().is_err());
+        assert!("https://example.com".parse::<Secret>().is_err());
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(
+            mask("000102030405060708090A0B0C0D0E0F"),
+            "00****************************0F"
+        );
+        assert_eq!(mask("12345678"), "********");
+        assert_eq!(mask(""), "");
+
+        let secret: Secret = "cmd:echo 0123456789abcdef0123".parse().unwrap();
+        assert!(!format!("{:?}", secret).contains("0123456789abcdef"));
+        let secret: Secret = "env:SOFTSIM_API_KEY".parse().unwrap();
+        assert_eq!(format!("{:?}", secret), "\"env:SOFTSIM_API_KEY\"");
+    }
+
+    #[test]
+    fn test_read_file_and_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "secret\nsecond line\n").unwrap();
+        assert_eq!(&*Secret::File(path.clone()).read("test").unwrap(), "secret");
+
+        std::fs::write(&path, "").unwrap();
+        assert!(Secret::File(path).read("test").is_err());
+
+        #[cfg(unix)]
+        {
+            let echo = Secret::Command(String::from("echo secret"));
+            assert_eq!(&*echo.read("test").unwrap(), "secret");
+            assert!(Secret::Command(String::from("false")).read("test").is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_vault() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let n = stream.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_lowercase();
+
+            let body = r#"{"data":{"data":{"api_key":"from-vault\n"},"metadata":{}}}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request
+        });
+
+        let var = |name: &str| match name {
+            "VAULT_ADDR" => Some(format!("http://{}", addr)),
+            "VAULT_TOKEN" => Some(String::from("test-token")),
+            _ => None,
+        };
+        let value = vault("secret/data/softsim", "api_key", var).unwrap();
+        assert_eq!(first_line(value), "from-vault");
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("get /v1/secret/data/softsim "));
+        assert!(request.contains("x-vault-token: test-token"));
+    }
+}
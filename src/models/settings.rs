@@ -0,0 +1,296 @@
+use super::profile::SecretString;
+use super::secret::Secret;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Profile directory used when no flag, environment variable or config file
+/// names one.
+pub const DEFAULT_PROFILES_PATH: &str = "./profiles";
+
+/// Settings that can be given as flags, as `SOFTSIM_*` environment variables
+/// or in the config file. Flags win over the environment, which wins over
+/// the config file.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// File holding the API key, `-` for stdin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<PathBuf>,
+    /// Any other place to read the API key from, e.g. a command or Vault.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_from: Option<Secret>,
+}
+
+impl Settings {
+    /// Settings from `SOFTSIM_*` environment variables. A key source that
+    /// can't be parsed is an error, falling back to another key would hide it.
+    pub fn from_env() -> Result<Settings, Box<dyn Error>> {
+        Settings::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Settings from the `SOFTSIM_*` variables `lookup` finds.
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Settings, Box<dyn Error>> {
+        let var = |name: &str| lookup(name).filter(|v| !v.is_empty());
+        let api_key_from = match var("SOFTSIM_API_KEY_FROM") {
+            Some(s) => Some(
+                s.parse()
+                    .map_err(|e| format!("Invalid SOFTSIM_API_KEY_FROM: {}", e))?,
+            ),
+            None => None,
+        };
+        Ok(Settings {
+            profiles_path: var("SOFTSIM_PROFILES_PATH").map(PathBuf::from),
+            url: var("SOFTSIM_URL"),
+            api_key: var("SOFTSIM_API_KEY"),
+            api_key_file: var("SOFTSIM_API_KEY_FILE").map(PathBuf::from),
+            api_key_from,
+        })
+    }
+
+    /// Fill in what isn't set from `fallback`. The ways to give the API key
+    /// count as one setting, so a key file never loses to a key given at a
+    /// lower level.
+    pub fn or(self, fallback: Settings) -> Settings {
+        let has_key =
+            self.api_key.is_some() || self.api_key_file.is_some() || self.api_key_from.is_some();
+        let (api_key, api_key_file, api_key_from) = if has_key {
+            (self.api_key, self.api_key_file, self.api_key_from)
+        } else {
+            (
+                fallback.api_key,
+                fallback.api_key_file,
+                fallback.api_key_from,
+            )
+        };
+        Settings {
+            profiles_path: self.profiles_path.or(fallback.profiles_path),
+            url: self.url.or(fallback.url),
+            api_key,
+            api_key_file,
+            api_key_from,
+        }
+    }
+
+    pub fn profiles_path(&self) -> PathBuf {
+        self.profiles_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_PROFILES_PATH))
+    }
+
+    /// Where the API key comes from, if it isn't given as is.
+    pub fn api_key_source(&self) -> Option<Secret> {
+        self.api_key_from
+            .clone()
+            .or_else(|| self.api_key_file.clone().map(Secret::File))
+    }
+
+    /// The API key, read from wherever it is kept.
+    pub fn api_key(&self) -> Result<Option<SecretString>, Box<dyn Error>> {
+        match (&self.api_key, self.api_key_source()) {
+            (Some(key), _) => Ok(Some(SecretString::from(key.as_str()))),
+            (None, Some(source)) => source.read("API key").map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// The config file. Settings at the top apply to every named profile, e.g.
+///
+/// ```toml
+/// profiles_path = "/var/lib/softsim"
+///
+/// [profiles.staging]
+/// url = "https://staging.example.com/sims/profiles"
+/// api_key_file = "/etc/softsim/staging.key"
+/// ```
+#[derive(Serialize, Deserialize, Default)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub defaults: Settings,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e).into())
+    }
+
+    /// Write the file readable by the owner only, as it may hold an API key.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut options = std::fs::File::options();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&tmp)?
+            .write_all(toml::to_string_pretty(self)?.as_bytes())?;
+        std::fs::rename(&tmp, path).map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            format!("Failed to write config file {}: {}", path.display(), e).into()
+        })
+    }
+
+    /// Settings of the named profile, falling back to the top of the file.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings, Box<dyn Error>> {
+        let Some(name) = profile else {
+            return Ok(self.defaults.clone());
+        };
+        match self.profiles.get(name) {
+            Some(settings) => Ok(settings.clone().or(self.defaults.clone())),
+            None => Err(format!("No profile '{}' in the config file", name).into()),
+        }
+    }
+}
+
+/// `softsim/config.toml` in the user's config directory, e.g.
+/// `$XDG_CONFIG_HOME/softsim/config.toml` on Linux.
+pub fn default_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("softsim").join("config.toml"))
+}
+
+/// The config file given with `--config` or `SOFTSIM_CONFIG`, else the one
+/// at the default path.
+pub fn path(config: Option<&Path>) -> Option<PathBuf> {
+    config
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os("SOFTSIM_CONFIG").map(PathBuf::from))
+        .or_else(default_path)
+}
+
+/// The profile given with `--profile` or `SOFTSIM_PROFILE`.
+pub fn profile_name(profile: Option<&str>) -> Option<String> {
+    profile
+        .map(String::from)
+        .or_else(|| std::env::var("SOFTSIM_PROFILE").ok())
+        .filter(|p| !p.is_empty())
+}
+
+/// Settings from the environment and the config file. A config file that
+/// was named explicitly must exist, the one at the default path may not.
+pub fn load(config: Option<&Path>, profile: Option<&str>) -> Result<Settings, Box<dyn Error>> {
+    let explicit = config.is_some() || std::env::var_os("SOFTSIM_CONFIG").is_some();
+    let profile = profile_name(profile);
+
+    let file = match path(config) {
+        Some(path) if explicit || path.exists() => ConfigFile::load(&path)?,
+        _ => ConfigFile::default(),
+    };
+    Ok(Settings::from_env()?.or(file.settings(profile.as_deref())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_key_source_in_env() {
+        let settings = Settings::from_vars(|name| {
+            (name == "SOFTSIM_API_KEY_FROM").then(|| String::from("0123456789abcdef"))
+        });
+        assert!(settings.is_err());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let flags = Settings {
+            api_key_file: Some(PathBuf::from("flag.key")),
+            ..Default::default()
+        };
+        let env = Settings {
+            url: Some(String::from("https://env")),
+            api_key: Some(String::from("env-key")),
+            ..Default::default()
+        };
+        let file = Settings {
+            profiles_path: Some(PathBuf::from("/pool")),
+            url: Some(String::from("https://file")),
+            api_key: Some(String::from("file-key")),
+            ..Default::default()
+        };
+
+        let settings = flags.or(env).or(file);
+        assert_eq!(settings.profiles_path(), PathBuf::from("/pool"));
+        assert_eq!(settings.url.as_deref(), Some("https://env"));
+        // the key file of the flag wins over keys given further down
+        assert_eq!(settings.api_key, None);
+        assert_eq!(settings.api_key_file, Some(PathBuf::from("flag.key")));
+
+        assert_eq!(
+            Settings::default().profiles_path(),
+            PathBuf::from(DEFAULT_PROFILES_PATH)
+        );
+    }
+
+    #[test]
+    fn test_named_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("softsim").join("config.toml");
+        std::fs::create_dir(dir.path().join("softsim")).unwrap();
+        std::fs::write(
+            &path,
+            r#"
+profiles_path = "/pool"
+url = "https://api"
+
+[profiles.staging]
+url = "https://staging"
+api_key_file = "staging.key"
+"#,
+        )
+        .unwrap();
+
+        let file = ConfigFile::load(&path).unwrap();
+        let staging = file.settings(Some("staging")).unwrap();
+        assert_eq!(staging.url.as_deref(), Some("https://staging"));
+        assert_eq!(staging.profiles_path, Some(PathBuf::from("/pool")));
+        assert_eq!(
+            file.settings(None).unwrap().url.as_deref(),
+            Some("https://api")
+        );
+        assert!(file.settings(Some("prod")).is_err());
+
+        file.save(&path).unwrap();
+        let saved = ConfigFile::load(&path).unwrap();
+        assert!(saved.settings(Some("staging")).unwrap() == staging);
+    }
+
+    #[test]
+    fn test_api_key_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, "secret\n").unwrap();
+
+        let file = Settings {
+            api_key_file: Some(path),
+            ..Default::default()
+        };
+        assert_eq!(file.api_key().unwrap().as_deref(), Some("secret"));
+
+        let flags = Settings {
+            api_key_from: Some("env:SOFTSIM_TEST_UNSET_API_KEY".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(flags.or(file).api_key().is_err());
+        assert_eq!(Settings::default().api_key().unwrap(), None);
+    }
+}
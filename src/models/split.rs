@@ -0,0 +1,241 @@
+use super::pool::{self, Lock, LowInventory};
+use super::store::{dir::DirStore, Store};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Extension of the file listing the profiles moved into a part. Written next
+/// to the part directory, so a copy stays behind when the part is shipped.
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Record of the profiles moved into one part of a split pool.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub part: String,
+    pub source: PathBuf,
+    pub created: DateTime<Utc>,
+    pub count: usize,
+    pub ranges: Vec<Range>,
+    pub iccids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub first: String,
+    pub last: String,
+    pub count: usize,
+}
+
+/// How to split a pool.
+#[derive(Debug)]
+pub enum Sizes {
+    /// All unused profiles into this many parts of equal size.
+    Equal(usize),
+    /// Parts of these sizes. Profiles left over stay in the pool.
+    Given(Vec<usize>),
+}
+
+/// Sizes of `parts` parts, as equal as possible, sharing all of `available`.
+pub fn equal_sizes(available: usize, parts: usize) -> Vec<usize> {
+    (0..parts)
+        .map(|i| available / parts + usize::from(i < available % parts))
+        .collect()
+}
+
+/// Move unused profiles out of the pool in `dir` into a directory per part
+/// under `out`, named `part-1`, `part-2` and so on. Each part gets the next
+/// profiles in ICCID order, so parts are disjoint ranges where possible.
+///
+/// The profiles are counted under the lock, so a `next` running at the same
+/// time can't leave the parts short.
+pub fn split(dir: &Path, out: &Path, sizes: &Sizes) -> Result<Vec<Manifest>, Box<dyn Error>> {
+    let _lock = Lock::acquire(dir)?;
+    let available = DirStore::new(dir).inventory()?.available;
+
+    let sizes = match sizes {
+        Sizes::Equal(parts) if available.len() < *parts => {
+            return Err(LowInventory {
+                available: available.len(),
+                minimum: *parts,
+            }
+            .into())
+        }
+        Sizes::Equal(parts) => equal_sizes(available.len(), *parts),
+        Sizes::Given(sizes) => sizes.clone(),
+    };
+
+    let wanted: usize = sizes.iter().sum();
+    if wanted > available.len() {
+        return Err(LowInventory {
+            available: available.len(),
+            minimum: wanted,
+        }
+        .into());
+    }
+
+    let names: Vec<String> = (1..=sizes.len()).map(|i| format!("part-{}", i)).collect();
+    for name in &names {
+        for path in [out.join(name), manifest_path(out, name)] {
+            if path.exists() {
+                return Err(format!(
+                    "{} already exists. Split into an empty directory",
+                    path.display()
+                )
+                .into());
+            }
+        }
+    }
+
+    let mut manifests: Vec<Manifest> = Vec::with_capacity(sizes.len());
+    let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut iccids = available.into_iter();
+    for (name, &size) in names.iter().zip(&sizes) {
+        let part: Vec<String> = iccids.by_ref().take(size).collect();
+        let part_dir = out.join(name);
+        let manifest = Manifest {
+            part: name.clone(),
+            source: dir.to_path_buf(),
+            created: Utc::now(),
+            count: part.len(),
+            ranges: pool::ranges(&part)
+                .into_iter()
+                .map(|(first, last, count)| Range { first, last, count })
+                .collect(),
+            iccids: part,
+        };
+
+        // written right away, a part without its manifest can't be traced
+        // back to the pool if the split dies half way
+        let res = std::fs::create_dir_all(&part_dir)
+            .map_err(|e| e.into())
+            .and_then(|_| move_profiles(dir, &part_dir, &manifest.iccids, &mut moved))
+            .and_then(|_| write_manifest(out, &manifest));
+        if let Err(e) = res {
+            undo(&moved);
+            for written in &manifests {
+                let path = manifest_path(out, &written.part);
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::error!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+            return Err(e);
+        }
+        manifests.push(manifest);
+    }
+
+    Ok(manifests)
+}
+
+fn write_manifest(out: &Path, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    let serialized = serde_json::to_string_pretty(manifest)?;
+    super::fs::write_atomic(&manifest_path(out, &manifest.part), serialized.as_bytes())
+}
+
+fn manifest_path(out: &Path, part: &str) -> PathBuf {
+    out.join(format!("{}.{}", part, MANIFEST_EXTENSION))
+}
+
+fn move_profiles(
+    from: &Path,
+    to: &Path,
+    iccids: &[String],
+    moved: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), Box<dyn Error>> {
+    for iccid in iccids {
+        let name = format!("{}.json", iccid);
+        let (src, dst) = (from.join(&name), to.join(&name));
+        super::fs::move_file(&src, &dst)
+            .map_err(|e| format!("Failed to move {}: {}", src.display(), e))?;
+        moved.push((src, dst));
+    }
+    Ok(())
+}
+
+/// Put every moved profile back, after a split failed half way.
+fn undo(moved: &[(PathBuf, PathBuf)]) {
+    for (src, dst) in moved.iter().rev() {
+        if let Err(e) = super::fs::move_file(dst, src) {
+            log::error!(
+                "Failed to move {} back to {}: {}",
+                dst.display(),
+                src.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pool::tests::pool_with;
+
+    #[test]
+    fn test_equal_sizes() {
+        assert_eq!(equal_sizes(10, 4), vec![3, 3, 2, 2]);
+        assert_eq!(equal_sizes(8, 4), vec![2, 2, 2, 2]);
+        assert_eq!(equal_sizes(1, 2), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_split_sizes() {
+        let dir = pool_with(10);
+        pool::claim(&DirStore::new(dir.path()), 1).unwrap();
+
+        let manifests = split(dir.path(), dir.path(), &Sizes::Given(vec![5, 3])).unwrap();
+        assert_eq!(manifests[0].iccids, vec!["001", "002", "003", "004", "005"]);
+        assert_eq!(manifests[1].iccids, vec!["006", "007", "008"]);
+        assert!(dir.path().join("part-2").join("008.json").exists());
+        assert!(dir.path().join("part-1.manifest").exists());
+
+        // the consumed profile and the one left over stay in the pool
+        let left = DirStore::new(dir.path()).inventory().unwrap();
+        assert_eq!(left.available, vec!["009"]);
+        assert_eq!(left.consumed, vec!["000"]);
+
+        let part = DirStore::new(&dir.path().join("part-1"))
+            .inventory()
+            .unwrap();
+        assert_eq!(part.available.len(), 5);
+    }
+
+    #[test]
+    fn test_split_equal_parts() {
+        let dir = pool_with(5);
+        pool::claim(&DirStore::new(dir.path()), 1).unwrap();
+
+        let manifests = split(dir.path(), dir.path(), &Sizes::Equal(3)).unwrap();
+        let counts: Vec<usize> = manifests.iter().map(|m| m.count).collect();
+        assert_eq!(counts, vec![2, 1, 1]);
+        assert!(DirStore::new(dir.path())
+            .inventory()
+            .unwrap()
+            .available
+            .is_empty());
+
+        let dir = pool_with(2);
+        assert!(split(dir.path(), dir.path(), &Sizes::Equal(3))
+            .unwrap_err()
+            .is::<LowInventory>());
+    }
+
+    #[test]
+    fn test_split_refuses_too_many_and_existing_parts() {
+        let dir = pool_with(3);
+        assert!(split(dir.path(), dir.path(), &Sizes::Given(vec![2, 2]))
+            .unwrap_err()
+            .is::<LowInventory>());
+
+        std::fs::create_dir(dir.path().join("part-2")).unwrap();
+        assert!(split(dir.path(), dir.path(), &Sizes::Given(vec![1, 1])).is_err());
+        assert_eq!(
+            DirStore::new(dir.path())
+                .inventory()
+                .unwrap()
+                .available
+                .len(),
+            3
+        );
+    }
+}
@@ -0,0 +1,99 @@
+pub mod dir;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use super::pool::Lock;
+use super::profile::EncryptedProfile;
+use std::error::Error;
+use std::path::Path;
+
+/// Where a profile is in its life cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Available,
+    Consumed,
+    Provisioned,
+}
+
+/// ICCIDs found in a store, sorted. Provisioned profiles count as consumed.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub available: Vec<String>,
+    pub consumed: Vec<String>,
+}
+
+impl Inventory {
+    pub fn total(&self) -> usize {
+        self.available.len() + self.consumed.len()
+    }
+}
+
+/// Keeps the encrypted profiles of a pool and which of them are used.
+///
+/// Methods taking a [`Lock`] change the pool and must only be called with
+/// the lock of [`Store::dir`] held, see the `pool` module. Reservations,
+/// device bindings and the audit log are kept as files in that directory
+/// whatever the backend.
+pub trait Store {
+    /// Directory holding the lock, reservations, device bindings and audit log.
+    fn dir(&self) -> &Path;
+
+    /// Mark up to `count` available profiles as consumed, lowest ICCID first.
+    /// Returns fewer if the pool runs out.
+    fn take(&self, count: usize, lock: &Lock) -> Result<Vec<EncryptedProfile>, Box<dyn Error>>;
+
+    /// Make a consumed profile available again.
+    fn put_back(&self, iccid: &str, lock: &Lock) -> Result<(), Box<dyn Error>>;
+
+    /// Mark a consumed profile as written to a device.
+    fn provision(&self, iccid: &str, lock: &Lock) -> Result<(), Box<dyn Error>>;
+
+    /// Move a consumed profile that can't be used, e.g. because it doesn't
+    /// decrypt, out of the pool. It is kept aside for someone to look at, but
+    /// no longer counts as available or consumed.
+    fn quarantine(&self, iccid: &str, lock: &Lock) -> Result<(), Box<dyn Error>>;
+
+    /// Swap the envelopes of profiles already in the store, whatever their
+    /// state. Either all of them are replaced or, if one of them can't be,
+    /// none. Readers see either the old or the new envelope of a profile.
+    fn replace_all(&self, profiles: &[EncryptedProfile], lock: &Lock)
+        -> Result<(), Box<dyn Error>>;
+
+    /// `None` if the store has no profile with this ICCID.
+    fn state(&self, iccid: &str) -> Result<Option<State>, Box<dyn Error>>;
+
+    fn get(&self, iccid: &str) -> Result<Option<EncryptedProfile>, Box<dyn Error>>;
+
+    fn inventory(&self) -> Result<Inventory, Box<dyn Error>>;
+
+    /// Remember the IMSI of a handed out profile, for [`Store::find_imsi`].
+    /// Only the ICCID is known before a profile is decrypted.
+    fn record_imsi(&self, iccid: &str, imsi: &str) -> Result<(), Box<dyn Error>>;
+
+    /// ICCID of the handed out profile with this IMSI.
+    fn find_imsi(&self, imsi: &str) -> Result<Option<String>, Box<dyn Error>>;
+}
+
+/// Open the store at `path`. A file with a `.db`, `.sqlite` or `.sqlite3`
+/// extension is a SQLite database, anything else a profile directory.
+pub fn open(path: &Path) -> Result<Box<dyn Store>, Box<dyn Error>> {
+    if !is_database(path) {
+        return Ok(Box::new(dir::DirStore::new(path)));
+    }
+
+    #[cfg(feature = "sqlite")]
+    return Ok(Box::new(sqlite::SqliteStore::open(path)?));
+
+    #[cfg(not(feature = "sqlite"))]
+    Err(format!(
+        "{} is a SQLite database, but softsim was built without the sqlite feature",
+        path.display()
+    )
+    .into())
+}
+
+pub fn is_database(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ["db", "sqlite", "sqlite3"].contains(&e))
+}
@@ -0,0 +1,356 @@
+use super::{Inventory, State, Store};
+use crate::models::audit;
+use crate::models::pool::Lock;
+use crate::models::profile::EncryptedProfile;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Prefix added to a profile file once it has been handed out.
+pub const CONSUMED_PREFIX: &str = "__";
+/// Extension of the marker written next to a profile confirmed as provisioned.
+pub const PROVISIONED_EXTENSION: &str = "provisioned";
+/// Extension a profile that can't be used is renamed to, out of the pool.
+pub const INVALID_EXTENSION: &str = "invalid";
+
+/// One `<iccid>.json` file per profile, as written by `fetch`.
+///
+/// A handed out profile is renamed to `__<iccid>.json`. A rename either
+/// succeeds for exactly one process or fails with `NotFound`, so stations
+/// sharing a directory can never hand out the same profile.
+#[derive(Debug)]
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    pub fn new(dir: &Path) -> DirStore {
+        DirStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn profile_path(&self, iccid: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", iccid))
+    }
+
+    fn provisioned_path(&self, iccid: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", iccid, PROVISIONED_EXTENSION))
+    }
+
+    fn invalid_path(&self, iccid: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", iccid, INVALID_EXTENSION))
+    }
+
+    /// Path of a profile in the pool, consumed or not.
+    fn existing_path(&self, iccid: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let original = self.profile_path(iccid);
+        [consumed_path(&original), original]
+            .into_iter()
+            .find(|p| p.exists())
+            .ok_or_else(|| format!("Profile {} is not in the pool", iccid).into())
+    }
+
+    fn put_back_all(&self, taken: &[EncryptedProfile], lock: &Lock) -> Result<(), Box<dyn Error>> {
+        for profile in taken {
+            self.put_back(profile.iccid(), lock)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<std::fs::ReadDir, Box<dyn Error>> {
+        std::fs::read_dir(&self.dir).map_err(|e| {
+            log::error!("Failed to read directory: {}", self.dir.display());
+            format!(
+                "Failed to read directory: {} Err: {}",
+                self.dir.display(),
+                e
+            )
+            .into()
+        })
+    }
+}
+
+impl Store for DirStore {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn take(&self, count: usize, lock: &Lock) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
+        let mut candidates: Vec<PathBuf> = self
+            .read_dir()?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_available(path))
+            .collect();
+        candidates.sort();
+
+        let mut taken = Vec::with_capacity(count);
+        for original in candidates {
+            if taken.len() == count {
+                break;
+            }
+            let claimed = consumed_path(&original);
+            match std::fs::rename(&original, &claimed) {
+                Ok(()) => {}
+                // someone without the lock got there first
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    self.put_back_all(&taken, lock)?;
+                    return Err(e.into());
+                }
+            }
+
+            match read(&claimed) {
+                Ok(profile) => taken.push(profile),
+                // put back, it would be the first one taken again, every time
+                Err(e) => {
+                    let iccid = iccid_of(&original);
+                    if let Err(e) = std::fs::rename(&claimed, self.invalid_path(&iccid)) {
+                        self.put_back_all(&taken, lock)?;
+                        return Err(e.into());
+                    }
+                    log::error!("Moved profile {} out of the pool: {}", iccid, e);
+                    audit::append(
+                        &self.dir,
+                        audit::Event::Quarantine {
+                            iccid,
+                            reason: e.to_string(),
+                        },
+                        lock,
+                    )?;
+                }
+            }
+        }
+
+        Ok(taken)
+    }
+
+    fn put_back(&self, iccid: &str, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+        let original = self.profile_path(iccid);
+        log::debug!("Returning {} to the pool", original.display());
+        std::fs::rename(consumed_path(&original), &original)?;
+        Ok(())
+    }
+
+    fn provision(&self, iccid: &str, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+        std::fs::File::options()
+            .create_new(true)
+            .write(true)
+            .open(self.provisioned_path(iccid))?;
+        Ok(())
+    }
+
+    fn quarantine(&self, iccid: &str, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+        let claimed = consumed_path(&self.profile_path(iccid));
+        std::fs::rename(&claimed, self.invalid_path(iccid))?;
+        Ok(())
+    }
+
+    fn replace_all(
+        &self,
+        profiles: &[EncryptedProfile],
+        _lock: &Lock,
+    ) -> Result<(), Box<dyn Error>> {
+        // every new envelope is on disk before the first profile is swapped,
+        // and every old one is kept until the last is
+        let mut staged: Vec<Staged> = Vec::with_capacity(profiles.len());
+        for profile in profiles {
+            let res = self
+                .existing_path(profile.iccid())
+                .and_then(|path| Staged::new(path, profile));
+            match res {
+                Ok(s) => staged.push(s),
+                Err(e) => {
+                    staged.iter().for_each(Staged::discard);
+                    return Err(e);
+                }
+            }
+        }
+
+        for (i, swap) in staged.iter().enumerate() {
+            if let Err(e) = std::fs::rename(&swap.new, &swap.path) {
+                log::error!("Failed to replace {}: {}", swap.path.display(), e);
+                staged[..i].iter().for_each(Staged::restore);
+                staged[i..].iter().for_each(Staged::discard);
+                return Err(e.into());
+            }
+        }
+
+        for swap in &staged {
+            if let Err(e) = std::fs::remove_file(&swap.old) {
+                log::warn!("Failed to remove {}: {}", swap.old.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self, iccid: &str) -> Result<Option<State>, Box<dyn Error>> {
+        let original = self.profile_path(iccid);
+        Ok(if consumed_path(&original).exists() {
+            if self.provisioned_path(iccid).exists() {
+                Some(State::Provisioned)
+            } else {
+                Some(State::Consumed)
+            }
+        } else if original.exists() {
+            Some(State::Available)
+        } else {
+            None
+        })
+    }
+
+    fn get(&self, iccid: &str) -> Result<Option<EncryptedProfile>, Box<dyn Error>> {
+        let original = self.profile_path(iccid);
+        for path in [consumed_path(&original), original] {
+            if path.exists() {
+                return read(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn inventory(&self) -> Result<Inventory, Box<dyn Error>> {
+        let mut inventory = Inventory::default();
+
+        for entry in self.read_dir()? {
+            let path = entry?.path();
+            if is_available(&path) {
+                inventory.available.push(iccid_of(&path));
+            } else if let Some(original) = unconsumed_path(&path) {
+                if is_available(&original) {
+                    inventory.consumed.push(iccid_of(&original));
+                }
+            }
+        }
+
+        inventory.available.sort();
+        inventory.consumed.sort();
+        Ok(inventory)
+    }
+
+    fn record_imsi(&self, _iccid: &str, _imsi: &str) -> Result<(), Box<dyn Error>> {
+        // already part of the issue event in the audit log
+        Ok(())
+    }
+
+    fn find_imsi(&self, imsi: &str) -> Result<Option<String>, Box<dyn Error>> {
+        // not indexed, the audit log is the only place the IMSI is kept
+        Ok(audit::read(&self.dir)?
+            .into_iter()
+            .rev()
+            .find_map(|r| match r.event {
+                audit::Event::Issue {
+                    iccid,
+                    imsi: Some(i),
+                    ..
+                } if i == imsi => Some(iccid),
+                _ => None,
+            }))
+    }
+}
+
+/// A profile file about to be replaced by [`DirStore::replace_all`], with
+/// the new envelope written next to it and a copy of the old one.
+struct Staged {
+    path: PathBuf,
+    new: PathBuf,
+    old: PathBuf,
+}
+
+impl Staged {
+    fn new(path: PathBuf, profile: &EncryptedProfile) -> Result<Staged, Box<dyn Error>> {
+        let with_extension = |extension: &str| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(extension);
+            PathBuf::from(name)
+        };
+        let staged = Staged {
+            new: with_extension(".new"),
+            old: with_extension(".old"),
+            path,
+        };
+
+        let written = std::fs::copy(&staged.path, &staged.old)
+            .and_then(|_| std::fs::File::create(&staged.new))
+            .and_then(|mut file| {
+                file.write_all(serde_json::to_string(profile)?.as_bytes())?;
+                file.sync_all()
+            });
+        if let Err(e) = written {
+            staged.discard();
+            return Err(format!("Failed to write {}: {}", staged.new.display(), e).into());
+        }
+        Ok(staged)
+    }
+
+    /// Put the old envelope back after it was replaced.
+    fn restore(&self) {
+        match std::fs::rename(&self.old, &self.path) {
+            Ok(()) => log::debug!("Restored {}", self.path.display()),
+            Err(e) => log::error!(
+                "Failed to restore {}, the old envelope is in {}: {}",
+                self.path.display(),
+                self.old.display(),
+                e
+            ),
+        }
+    }
+
+    /// Drop the new envelope and the copy of the old one.
+    fn discard(&self) {
+        for path in [&self.new, &self.old] {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<EncryptedProfile, Box<dyn Error>> {
+    let file = std::fs::File::open(path)?;
+    let reader = std::io::BufReader::new(file);
+    let encrypted_profile = serde_json::from_reader(reader)
+        .map_err(|e| format!("Failed to parse encrypted profile. Is the file corrupted? {e}"))?;
+
+    Ok(encrypted_profile)
+}
+
+/// Returns true for files holding an unused encrypted profile.
+pub fn is_available(path: &Path) -> bool {
+    let stem = path
+        .file_stem()
+        .unwrap_or_else(|| std::ffi::OsStr::new(""))
+        .to_str()
+        .unwrap_or("");
+
+    !stem.is_empty()
+        && !stem.starts_with(CONSUMED_PREFIX)
+        && !stem.starts_with("profiles")
+        && path
+            .extension()
+            .unwrap_or(std::ffi::OsStr::new("notjson"))
+            .eq("json")
+}
+
+fn consumed_path(path: &Path) -> PathBuf {
+    let mut filename = std::ffi::OsString::from(CONSUMED_PREFIX);
+    filename.push(path.file_name().unwrap_or_default());
+    path.with_file_name(filename)
+}
+
+fn iccid_of(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The path a consumed profile had before it was claimed.
+fn unconsumed_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let original = name.strip_prefix(CONSUMED_PREFIX)?;
+    Some(path.with_file_name(original))
+}
@@ -0,0 +1,411 @@
+use super::{Inventory, State, Store};
+use crate::models::audit;
+use crate::models::pool::Lock;
+use crate::models::profile::EncryptedProfile;
+use rusqlite::{
+    params, Connection, OpenFlags, OptionalExtension, Transaction, TransactionBehavior,
+};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS profiles (
+    iccid TEXT PRIMARY KEY NOT NULL,
+    envelope TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'available'
+        CHECK (state IN ('available', 'consumed', 'provisioned')),
+    imsi TEXT,
+    updated TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS profiles_state ON profiles (state, iccid);
+CREATE INDEX IF NOT EXISTS profiles_imsi ON profiles (imsi);
+CREATE TABLE IF NOT EXISTS invalid_profiles (
+    iccid TEXT PRIMARY KEY NOT NULL,
+    envelope TEXT NOT NULL,
+    updated TEXT NOT NULL
+);
+";
+
+/// All profiles of a pool in one SQLite database.
+///
+/// Claiming is a single transaction and lookups are indexed, so this scales
+/// to pools far larger than a directory listing does. Reservations, device
+/// bindings and the audit log are kept next to the database file.
+pub struct SqliteStore {
+    conn: Connection,
+    dir: PathBuf,
+}
+
+impl SqliteStore {
+    /// Open an existing database.
+    pub fn open(path: &Path) -> Result<SqliteStore, Box<dyn Error>> {
+        let conn =
+            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE).map_err(|e| {
+                log::error!("Failed to open database {}: {}", path.display(), e);
+                e
+            })?;
+        SqliteStore::new(conn, path)
+    }
+
+    /// Open a database, creating it if needed.
+    pub fn create(path: &Path) -> Result<SqliteStore, Box<dyn Error>> {
+        let conn = Connection::open(path).map_err(|e| {
+            log::error!("Failed to create database {}: {}", path.display(), e);
+            e
+        })?;
+        SqliteStore::new(conn, path)
+    }
+
+    fn new(conn: Connection, path: &Path) -> Result<SqliteStore, Box<dyn Error>> {
+        // another station may be in the middle of a claim
+        conn.busy_timeout(Duration::from_secs(30))?;
+        conn.execute_batch(SCHEMA)?;
+
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(SqliteStore { conn, dir })
+    }
+
+    /// Copy every profile of `from` with its state. Profiles already in the
+    /// database are left alone. Returns the number of profiles added.
+    pub fn import(&self, from: &dyn Store) -> Result<usize, Box<dyn Error>> {
+        let inventory = from.inventory()?;
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+
+        let mut added = 0;
+        for iccid in inventory.available.iter().chain(&inventory.consumed) {
+            let (Some(profile), Some(state)) = (from.get(iccid)?, from.state(iccid)?) else {
+                continue;
+            };
+            added += tx.execute(
+                "INSERT OR IGNORE INTO profiles (iccid, envelope, state, updated)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    iccid,
+                    serde_json::to_string(&profile)?,
+                    state_name(state),
+                    now()
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(added)
+    }
+
+    /// Move `iccid` from state `from` to `to`, failing if it isn't in `from`.
+    fn transition(&self, iccid: &str, from: State, to: State) -> Result<(), Box<dyn Error>> {
+        let changed = self.conn.execute(
+            "UPDATE profiles SET state = ?3, updated = ?4 WHERE iccid = ?1 AND state = ?2",
+            params![iccid, state_name(from), state_name(to), now()],
+        )?;
+        if changed == 0 {
+            return Err(format!("Profile {} is not {}", iccid, state_name(from)).into());
+        }
+        Ok(())
+    }
+}
+
+impl Store for SqliteStore {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn take(&self, count: usize, lock: &Lock) -> Result<Vec<EncryptedProfile>, Box<dyn Error>> {
+        // IMMEDIATE takes the write lock up front, so two stations can't
+        // select the same rows
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+
+        let mut taken = Vec::with_capacity(count);
+        let mut invalid = Vec::new();
+        while taken.len() < count {
+            let rows: Vec<(String, String)> = tx
+                .prepare(
+                    "SELECT iccid, envelope FROM profiles
+                     WHERE state = 'available' ORDER BY iccid LIMIT ?1",
+                )?
+                .query_map([(count - taken.len()) as i64], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<Result<_, _>>()?;
+            if rows.is_empty() {
+                break;
+            }
+
+            for (iccid, envelope) in rows {
+                match serde_json::from_str(&envelope) {
+                    Ok(profile) => {
+                        tx.execute(
+                            "UPDATE profiles SET state = 'consumed', updated = ?2 WHERE iccid = ?1",
+                            params![iccid, now()],
+                        )?;
+                        taken.push(profile);
+                    }
+                    // left available, it would be the first one selected again, every time
+                    Err(e) => {
+                        tx.execute(
+                            "INSERT OR REPLACE INTO invalid_profiles (iccid, envelope, updated)
+                             VALUES (?1, ?2, ?3)",
+                            params![iccid, envelope, now()],
+                        )?;
+                        tx.execute("DELETE FROM profiles WHERE iccid = ?1", [&iccid])?;
+                        let reason = format!(
+                            "Failed to parse encrypted profile. Is the database corrupted? {}",
+                            e
+                        );
+                        invalid.push((iccid, reason));
+                    }
+                }
+            }
+        }
+
+        tx.commit()?;
+        for (iccid, reason) in invalid {
+            log::error!("Moved profile {} out of the pool: {}", iccid, reason);
+            audit::append(&self.dir, audit::Event::Quarantine { iccid, reason }, lock)?;
+        }
+        Ok(taken)
+    }
+
+    fn put_back(&self, iccid: &str, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+        log::debug!("Returning {} to the pool", iccid);
+        self.transition(iccid, State::Consumed, State::Available)
+    }
+
+    fn provision(&self, iccid: &str, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+        self.transition(iccid, State::Consumed, State::Provisioned)
+    }
+
+    fn quarantine(&self, iccid: &str, _lock: &Lock) -> Result<(), Box<dyn Error>> {
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let moved = tx.execute(
+            "INSERT OR REPLACE INTO invalid_profiles (iccid, envelope, updated)
+             SELECT iccid, envelope, ?2 FROM profiles WHERE iccid = ?1 AND state = 'consumed'",
+            params![iccid, now()],
+        )?;
+        if moved == 0 {
+            return Err(format!("Profile {} is not consumed", iccid).into());
+        }
+        tx.execute("DELETE FROM profiles WHERE iccid = ?1", [iccid])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn replace_all(
+        &self,
+        profiles: &[EncryptedProfile],
+        _lock: &Lock,
+    ) -> Result<(), Box<dyn Error>> {
+        // rolled back when dropped without a commit
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        for profile in profiles {
+            let changed = tx.execute(
+                "UPDATE profiles SET envelope = ?2, updated = ?3 WHERE iccid = ?1",
+                params![profile.iccid(), serde_json::to_string(profile)?, now()],
+            )?;
+            if changed == 0 {
+                return Err(format!("Profile {} is not in the pool", profile.iccid()).into());
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn state(&self, iccid: &str) -> Result<Option<State>, Box<dyn Error>> {
+        let state: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT state FROM profiles WHERE iccid = ?1",
+                [iccid],
+                |row| row.get(0),
+            )
+            .optional()?;
+        state.map(|s| parse_state(&s)).transpose()
+    }
+
+    fn get(&self, iccid: &str) -> Result<Option<EncryptedProfile>, Box<dyn Error>> {
+        let envelope: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT envelope FROM profiles WHERE iccid = ?1",
+                [iccid],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(envelope.map(|e| serde_json::from_str(&e)).transpose()?)
+    }
+
+    fn inventory(&self) -> Result<Inventory, Box<dyn Error>> {
+        let mut inventory = Inventory::default();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT iccid, state FROM profiles ORDER BY iccid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let iccid: String = row.get(0)?;
+            match parse_state(&row.get::<_, String>(1)?)? {
+                State::Available => inventory.available.push(iccid),
+                State::Consumed | State::Provisioned => inventory.consumed.push(iccid),
+            }
+        }
+        Ok(inventory)
+    }
+
+    fn record_imsi(&self, iccid: &str, imsi: &str) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE profiles SET imsi = ?2 WHERE iccid = ?1",
+            params![iccid, imsi],
+        )?;
+        Ok(())
+    }
+
+    fn find_imsi(&self, imsi: &str) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT iccid FROM profiles WHERE imsi = ?1",
+                [imsi],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
+
+fn state_name(state: State) -> &'static str {
+    match state {
+        State::Available => "available",
+        State::Consumed => "consumed",
+        State::Provisioned => "provisioned",
+    }
+}
+
+fn parse_state(s: &str) -> Result<State, Box<dyn Error>> {
+    match s {
+        "available" => Ok(State::Available),
+        "consumed" => Ok(State::Consumed),
+        "provisioned" => Ok(State::Provisioned),
+        _ => Err(format!("Unknown profile state '{}'", s).into()),
+    }
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::pool::{self, tests::pool_with};
+    use crate::models::store::dir::DirStore;
+
+    fn imported(n: usize) -> (tempfile::TempDir, SqliteStore) {
+        let dir = pool_with(n);
+        let store = SqliteStore::create(&dir.path().join("pool.db")).unwrap();
+        assert_eq!(store.import(&DirStore::new(dir.path())).unwrap(), n);
+        (dir, store)
+    }
+
+    #[test]
+    fn test_import_keeps_state() {
+        let dir = pool_with(3);
+        let source = DirStore::new(dir.path());
+        pool::claim(&source, 1).unwrap();
+        pool::confirm(&source, "000", "me").unwrap();
+        pool::claim(&source, 1).unwrap();
+
+        let store = SqliteStore::create(&dir.path().join("pool.db")).unwrap();
+        assert_eq!(store.import(&source).unwrap(), 3);
+        assert_eq!(store.import(&source).unwrap(), 0);
+
+        assert_eq!(store.state("000").unwrap(), Some(State::Provisioned));
+        assert_eq!(store.state("001").unwrap(), Some(State::Consumed));
+        assert_eq!(store.state("002").unwrap(), Some(State::Available));
+        assert_eq!(store.get("002").unwrap().unwrap().iccid(), "002");
+    }
+
+    #[test]
+    fn test_claim_release_confirm() {
+        let (_dir, store) = imported(3);
+
+        assert!(pool::claim(&store, 4)
+            .unwrap_err()
+            .is::<pool::LowInventory>());
+        let claims = pool::claim(&store, 2).unwrap();
+        assert_eq!(claims[0].iccid(), "000");
+        assert_eq!(claims[1].iccid(), "001");
+        assert_eq!(store.inventory().unwrap().available, vec!["002"]);
+
+        pool::release(&store, "000", "me", "test").unwrap();
+        pool::confirm(&store, "001", "me").unwrap();
+        assert!(pool::release(&store, "001", "me", "test").is_err());
+        assert_eq!(store.inventory().unwrap().available, vec!["000", "002"]);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let (_dir, store) = imported(2);
+        pool::claim(&store, 1).unwrap();
+
+        pool::quarantine(&store, "000", "doesn't decrypt").unwrap();
+        assert_eq!(store.state("000").unwrap(), None);
+        assert_eq!(store.inventory().unwrap().total(), 1);
+        assert!(pool::quarantine(&store, "001", "not claimed").is_err());
+    }
+
+    #[test]
+    fn test_take_skips_corrupt_profile() {
+        let (dir, store) = imported(3);
+        store
+            .conn
+            .execute("UPDATE profiles SET envelope = '{' WHERE iccid = '000'", [])
+            .unwrap();
+
+        let claims = pool::claim(&store, 2).unwrap();
+        assert_eq!(claims[0].iccid(), "001");
+        assert_eq!(claims[1].iccid(), "002");
+        assert_eq!(store.state("000").unwrap(), None);
+        assert!(matches!(
+            &audit::read(dir.path()).unwrap()[0].event,
+            audit::Event::Quarantine { iccid, .. } if iccid == "000"
+        ));
+    }
+
+    #[test]
+    fn test_replace_all_is_all_or_nothing() {
+        let (_dir, store) = imported(1);
+        let before = store.get("000").unwrap().unwrap();
+        let profile = |iccid: &str| -> EncryptedProfile {
+            serde_json::from_str(&format!(r#"{{"iccid":"{}","profile":"new"}}"#, iccid)).unwrap()
+        };
+
+        let lock = Lock::acquire(store.dir()).unwrap();
+        assert!(store
+            .replace_all(&[profile("000"), profile("001")], &lock)
+            .is_err());
+        assert_eq!(store.get("000").unwrap().unwrap(), before);
+
+        store.replace_all(&[profile("000")], &lock).unwrap();
+        assert_eq!(store.get("000").unwrap().unwrap().profile(), "new");
+    }
+
+    #[test]
+    fn test_find_imsi() {
+        let (_dir, store) = imported(1);
+        store.record_imsi("000", "234602102350049").unwrap();
+
+        assert_eq!(
+            store.find_imsi("234602102350049").unwrap().as_deref(),
+            Some("000")
+        );
+        assert!(store.find_imsi("234602102350040").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_open_requires_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(SqliteStore::open(&dir.path().join("missing.db")).is_err());
+    }
+}